@@ -0,0 +1,71 @@
+//! Records the version of wasmtime the host is built against, which wasmtime
+//! itself doesn't expose, for the metadata written next to compiled guests.
+//!
+//! The version comes from the lock file cargo resolved this build with. A
+//! build without one, or one that can't tell which wasmtime the host links,
+//! fails rather than stamping artifacts with a version that might be wrong.
+
+use std::path::{Path, PathBuf};
+
+fn main() {
+    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    // Built as a dependency, the lock file belongs to the workspace above.
+    let Some(lock) = manifest_dir
+        .ancestors()
+        .map(|dir| dir.join("Cargo.lock"))
+        .find(|lock| lock.exists())
+    else {
+        panic!(
+            "no Cargo.lock in {} or above it; run `cargo generate-lockfile` so the wasmtime version can be recorded",
+            manifest_dir.display()
+        );
+    };
+    let version = wasmtime_version(&lock).unwrap_or_else(|e| panic!("{}: {e}", lock.display()));
+    println!("cargo:rerun-if-changed={}", lock.display());
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-env=WASMTIME_VERSION={version}");
+}
+
+/// Finds the wasmtime this package depends on. Lock files name a dependency
+/// by name alone when only one version of it is locked, and add the version
+/// when there are several.
+fn wasmtime_version(lock: &Path) -> Result<String, String> {
+    let text = std::fs::read_to_string(lock).map_err(|e| e.to_string())?;
+    let lock: toml::Table = text.parse().map_err(|e| format!("{e}"))?;
+    let packages = lock
+        .get("package")
+        .and_then(|p| p.as_array())
+        .ok_or("no packages")?;
+    let field = |package: &toml::Value, key: &str| {
+        package
+            .get(key)
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+    };
+
+    let name = std::env::var("CARGO_PKG_NAME").unwrap();
+    let version = std::env::var("CARGO_PKG_VERSION").unwrap();
+    let this = packages
+        .iter()
+        .find(|p| {
+            field(p, "name").as_deref() == Some(&name)
+                && field(p, "version").as_deref() == Some(&version)
+        })
+        .ok_or_else(|| format!("{name} {version} isn't locked"))?;
+    let dependency = this
+        .get("dependencies")
+        .and_then(|d| d.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|d| d.as_str())
+        .find(|d| *d == "wasmtime" || d.starts_with("wasmtime "))
+        .ok_or_else(|| format!("{name} {version} doesn't depend on wasmtime"))?;
+    if let Some(version) = dependency.strip_prefix("wasmtime ") {
+        return Ok(version.to_string());
+    }
+    packages
+        .iter()
+        .find(|p| field(p, "name").as_deref() == Some("wasmtime"))
+        .and_then(|p| field(p, "version"))
+        .ok_or_else(|| "wasmtime isn't locked".to_string())
+}
@@ -0,0 +1,110 @@
+/*
+ * C API of the trace guest host, exported by libhost.so.
+ *
+ * An engine runs one guest. Requests pushed into it are queued for the
+ * guest's `queue.try-pop`, and messages the guest logs to endpoints whose
+ * sink is "buffer" are kept until drained. host_engine_push and
+ * host_engine_drain_logs may be called from several threads at once; the
+ * other calls need the engine to themselves.
+ *
+ * Functions taking `char **error` store a message there when they fail,
+ * unless it is NULL. Release it with host_error_free.
+ */
+#ifndef HOST_H
+#define HOST_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Compare with host_api_version() to check the library matches. */
+#define HOST_API_VERSION 2
+
+#define HOST_OK 0
+#define HOST_ERROR 1
+#define HOST_QUEUE_FULL 2
+#define HOST_CLOSED 3
+
+typedef struct host_engine host_engine;
+
+/* A request header. `name` is NUL-terminated; `value` is `value_len` bytes. */
+typedef struct host_header {
+    const char *name;
+    const uint8_t *value;
+    size_t value_len;
+} host_header;
+
+/*
+ * A guest log message, valid only during the callback it is passed to. The
+ * strings are not NUL-terminated.
+ */
+typedef struct host_log_record {
+    uint64_t timestamp_ms;
+    const char *endpoint;
+    size_t endpoint_len;
+    const char *sid;
+    size_t sid_len;
+    const char *msg;
+    size_t msg_len;
+} host_log_record;
+
+typedef void (*host_log_fn)(const host_log_record *record, void *data);
+
+uint32_t host_api_version(void);
+
+/*
+ * Creates an engine from a host config in TOML, the same format `host run
+ * --config` reads, or the defaults if `config` is NULL. Returns NULL on
+ * failure.
+ *
+ * The defaults, like a config without a [sinks] table, route no endpoints:
+ * every message the guest logs is rejected and host_engine_drain_logs never
+ * has anything to pass on. Map endpoints to "buffer" to drain them.
+ */
+host_engine *host_engine_new(const char *config, char **error);
+
+/*
+ * Loads a guest, a .cwasm compiled with the same engine settings or a
+ * component, and starts entering it on a background thread.
+ */
+int host_engine_load(host_engine *engine, const char *path, char **error);
+
+/*
+ * Queues a request for the guest without waiting. Returns HOST_QUEUE_FULL
+ * if there's no room and HOST_CLOSED once the engine has been closed.
+ */
+int host_engine_push(host_engine *engine, const char *method, const char *url,
+                     const host_header *headers, size_t n_headers,
+                     const char *service_id, char **error);
+
+/*
+ * Passes each message logged to a "buffer" sink since the last drain to
+ * `callback`, oldest first. Returns how many there were, or 0 if the engine
+ * has no guest loaded or the host failed internally. A NULL `callback`
+ * drains nothing and returns 0.
+ */
+size_t host_engine_drain_logs(host_engine *engine, host_log_fn callback,
+                              void *data);
+
+/*
+ * Stops accepting requests, waits for the guest to handle the ones already
+ * queued and flushes the sinks. Logs can still be drained afterwards.
+ */
+int host_engine_close(host_engine *engine, char **error);
+
+/*
+ * Closes the engine if that hasn't been done and releases it, even if
+ * closing fails. Returns HOST_ERROR if it did.
+ */
+int host_engine_free(host_engine *engine, char **error);
+
+void host_error_free(char *error);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* HOST_H */
@@ -0,0 +1,150 @@
+/*
+ * Exercises the C API against a guest: bad configs and paths are reported,
+ * pushed requests reach the guest, its logs can be drained, and pushing
+ * after close is refused.
+ *
+ *     test_host GUEST
+ *
+ * GUEST can be any trace guest that logs "entered" to the cmcd endpoint
+ * when entered, something for each request, and "done" once the queue is
+ * closed, such as guest_rust_trace.
+ */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "host.h"
+
+#define N_REQUESTS 3
+
+static int failures;
+
+#define CHECK(cond, ...)                                                     \
+    do {                                                                     \
+        if (!(cond)) {                                                       \
+            fprintf(stderr, "FAIL %s:%d: %s: ", __FILE__, __LINE__, #cond); \
+            fprintf(stderr, __VA_ARGS__);                                    \
+            fprintf(stderr, "\n");                                           \
+            failures++;                                                      \
+        }                                                                    \
+    } while (0)
+
+struct logs {
+    size_t count;
+    char first[64];
+    char last[64];
+};
+
+static void copy_msg(char *dst, const host_log_record *r) {
+    size_t len = r->msg_len < 63 ? r->msg_len : 63;
+    memcpy(dst, r->msg, len);
+    dst[len] = '\0';
+}
+
+static void on_log(const host_log_record *r, void *data) {
+    struct logs *logs = data;
+    printf("log %.*s/%.*s: %.*s\n", (int)r->endpoint_len, r->endpoint,
+           (int)r->sid_len, r->sid, (int)r->msg_len, r->msg);
+    if (logs->count == 0)
+        copy_msg(logs->first, r);
+    copy_msg(logs->last, r);
+    logs->count++;
+}
+
+static void test_bad_config(void) {
+    char *error = NULL;
+    host_engine *engine = host_engine_new("[epoch]\ntick_ms = 0\n", &error);
+    CHECK(engine == NULL, "engine created from an invalid config");
+    CHECK(error && strstr(error, "epoch.tick_ms"),
+          "error doesn't name the key: %s", error ? error : "(null)");
+    host_error_free(error);
+}
+
+static void test_bad_path(void) {
+    char *error = NULL;
+    host_engine *engine = host_engine_new(NULL, &error);
+    CHECK(engine != NULL, "%s", error);
+    int status = host_engine_load(engine, "/nonexistent.cwasm", &error);
+    CHECK(status == HOST_ERROR, "loading a missing file returned %d", status);
+    CHECK(error != NULL, "no error message");
+    host_error_free(error);
+    CHECK(host_engine_free(engine, NULL) == HOST_OK, "free failed");
+}
+
+static void test_requests(const char *guest) {
+    static const char config[] =
+        "[sinks]\n"
+        "cmcd = \"buffer\"\n"
+        "[timeouts]\n"
+        "max_pop_wait_ms = 100\n";
+    char *error = NULL;
+    host_engine *engine = host_engine_new(config, &error);
+    CHECK(engine != NULL, "%s", error);
+    if (!engine) {
+        host_error_free(error);
+        return;
+    }
+
+    int status = host_engine_load(engine, guest, &error);
+    CHECK(status == HOST_OK, "loading %s: %s", guest, error);
+    if (status != HOST_OK) {
+        host_error_free(error);
+        host_engine_free(engine, NULL);
+        return;
+    }
+
+    for (int i = 0; i < N_REQUESTS; i++) {
+        char url[32], cmcd[32];
+        snprintf(url, sizeof url, "/segment/%d.ts", i);
+        snprintf(cmcd, sizeof cmcd, "br=%d", 1000 * (i + 1));
+        host_header headers[] = {
+            {"host", (const uint8_t *)"example.com", 11},
+            {"cmcd-request", (const uint8_t *)cmcd, strlen(cmcd)},
+        };
+        status = host_engine_push(engine, "GET", url, headers, 2, "sid-c",
+                                  &error);
+        CHECK(status == HOST_OK, "push %d returned %d: %s", i, status,
+              error ? error : "");
+    }
+
+    status = host_engine_close(engine, &error);
+    CHECK(status == HOST_OK, "close: %s", error);
+
+    CHECK(host_engine_drain_logs(engine, NULL, NULL) == 0,
+          "drained logs without a callback");
+    struct logs logs = {0};
+    size_t drained = host_engine_drain_logs(engine, on_log, &logs);
+    CHECK(drained == logs.count, "drained %zu but saw %zu", drained,
+          logs.count);
+    CHECK(logs.count >= N_REQUESTS + 2, "only %zu log records", logs.count);
+    CHECK(strcmp(logs.first, "entered") == 0, "first log is %s", logs.first);
+    CHECK(strcmp(logs.last, "done") == 0, "last log is %s", logs.last);
+    CHECK(host_engine_drain_logs(engine, on_log, &logs) == 0,
+          "logs drained twice");
+
+    status = host_engine_push(engine, "GET", "/late", NULL, 0, "sid-c", &error);
+    CHECK(status == HOST_CLOSED, "push after close returned %d", status);
+
+    status = host_engine_free(engine, &error);
+    CHECK(status == HOST_OK, "free: %s", error);
+}
+
+int main(int argc, char **argv) {
+    if (argc != 2) {
+        fprintf(stderr, "usage: %s GUEST\n", argv[0]);
+        return 2;
+    }
+    CHECK(host_api_version() == HOST_API_VERSION, "library API version %u",
+          host_api_version());
+
+    test_bad_config();
+    test_bad_path();
+    test_requests(argv[1]);
+
+    if (failures) {
+        fprintf(stderr, "%d checks failed\n", failures);
+        return 1;
+    }
+    printf("all checks passed\n");
+    return 0;
+}
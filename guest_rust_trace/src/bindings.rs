@@ -228,21 +228,39 @@ pub mod fastly {
             #[doc(hidden)]
             static __FORCE_SECTION_REF: fn() = super::super::super::__link_custom_section_describing_imports;
             use super::super::super::_rt;
+            pub type ReqResource = super::super::super::fastly::varnish::types::ReqResource;
             #[allow(unused_unsafe, clippy::all)]
-            pub fn try_pop(timeout_secs: u64) -> bool {
+            pub fn try_pop(timeout_secs: u64) -> Option<ReqResource> {
                 unsafe {
+                    #[repr(align(4))]
+                    struct RetArea([::core::mem::MaybeUninit<u8>; 8]);
+                    let mut ret_area = RetArea([::core::mem::MaybeUninit::uninit(); 8]);
+                    let ptr0 = ret_area.0.as_mut_ptr().cast::<u8>();
                     #[cfg(target_arch = "wasm32")]
                     #[link(wasm_import_module = "fastly:varnish/queue")]
                     extern "C" {
                         #[link_name = "try-pop"]
-                        fn wit_import(_: i64) -> i32;
+                        fn wit_import(_: i64, _: *mut u8);
                     }
                     #[cfg(not(target_arch = "wasm32"))]
-                    fn wit_import(_: i64) -> i32 {
+                    fn wit_import(_: i64, _: *mut u8) {
                         unreachable!()
                     }
-                    let ret = wit_import(_rt::as_i64(&timeout_secs));
-                    _rt::bool_lift(ret as u8)
+                    wit_import(_rt::as_i64(&timeout_secs), ptr0);
+                    let l1 = i32::from(*ptr0.add(0).cast::<u8>());
+                    match l1 {
+                        0 => None,
+                        1 => {
+                            let e = {
+                                let l2 = *ptr0.add(4).cast::<i32>();
+                                super::super::super::fastly::varnish::types::ReqResource::from_handle(
+                                    l2 as u32,
+                                )
+                            };
+                            Some(e)
+                        }
+                        _ => _rt::invalid_enum_discriminant(),
+                    }
                 }
             }
         }
@@ -405,17 +423,6 @@ mod _rt {
             self as i64
         }
     }
-    pub unsafe fn bool_lift(val: u8) -> bool {
-        if cfg!(debug_assertions) {
-            match val {
-                0 => false,
-                1 => true,
-                _ => panic!("invalid bool discriminant"),
-            }
-        } else {
-            val != 0
-        }
-    }
     #[cfg(target_arch = "wasm32")]
     pub fn run_ctors_once() {
         wit_bindgen_rt::run_ctors_once();
@@ -456,20 +463,20 @@ pub(crate) use __export_trace_impl as export;
 #[cfg(target_arch = "wasm32")]
 #[link_section = "component-type:wit-bindgen:0.30.0:trace:encoded world"]
 #[doc(hidden)]
-pub static __WIT_BINDGEN_COMPONENT_TYPE: [u8; 577] = *b"\
-\0asm\x0d\0\x01\0\0\x19\x16wit-component-encoding\x04\0\x07\xc5\x03\x01A\x02\x01\
-A\x09\x01B\x02\x01@\x03\x03msgs\x08endpoints\x03sids\x01\0\x04\0\x03log\x01\0\x03\
-\x01\x18fastly:varnish/trace-log\x05\0\x01B\x0b\x04\0\x0creq-resource\x03\x01\x01\
-h\0\x01p}\x01p\x02\x01@\x01\x04self\x01\0\x03\x04\0%[method]req-resource.get-hea\
-der-names\x01\x04\x01k\x03\x01@\x02\x04self\x01\x06headers\0\x05\x04\0\x18[metho\
-d]req-resource.get\x01\x06\x01@\x01\x04self\x01\0s\x04\0#[method]req-resource.ge\
-t-service-id\x01\x07\x03\x01\x14fastly:varnish/types\x05\x01\x02\x03\0\x01\x0cre\
-q-resource\x01B\x04\x02\x03\x02\x01\x02\x04\0\x0creq-resource\x03\0\0\x01@\x01\x0c\
-timeout-secsw\0\x7f\x04\0\x07try-pop\x01\x02\x03\x01\x14fastly:varnish/queue\x05\
-\x03\x01B\x02\x01@\0\x01\0\x04\0\x05enter\x01\0\x04\x01\x1afastly:varnish/trace-\
-hooks\x05\x04\x04\x01\x14fastly:varnish/trace\x04\0\x0b\x0b\x01\0\x05trace\x03\0\
-\0\0G\x09producers\x01\x0cprocessed-by\x02\x0dwit-component\x070.215.0\x10wit-bi\
-ndgen-rust\x060.30.0";
+pub static __WIT_BINDGEN_COMPONENT_TYPE: [u8; 583] = *b"\
+\0asm\x0d\0\x01\0\0\x19\x16wit-component-encoding\x04\0\x07\xcb\x03\x01A\x02\x01\
+A\x09\x01B\x02\x01@\x03\x03msgs\x08endpoints\x03sids\x01\0\x04\0\x03log\x01\0\
+\x03\x01\x18fastly:varnish/trace-log\x05\0\x01B\x0b\x04\0\x0creq-resource\x03\
+\x01\x01h\0\x01p}\x01p\x02\x01@\x01\x04self\x01\0\x03\x04\0%[method]req-resource\
+.get-header-names\x01\x04\x01k\x03\x01@\x02\x04self\x01\x06headers\0\x05\x04\0\
+\x18[method]req-resource.get\x01\x06\x01@\x01\x04self\x01\0s\x04\0#[method]req-r\
+esource.get-service-id\x01\x07\x03\x01\x14fastly:varnish/types\x05\x01\x02\x03\0\
+\x01\x0creq-resource\x01B\x06\x02\x03\x02\x01\x02\x04\0\x0creq-resource\x03\0\0\
+\x01i\x01\x01k\x02\x01@\x01\x0ctimeout-secsw\0\x03\x04\0\x07try-pop\x01\x04\x03\
+\x01\x14fastly:varnish/queue\x05\x03\x01B\x02\x01@\0\x01\0\x04\0\x05enter\x01\0\
+\x04\x01\x1afastly:varnish/trace-hooks\x05\x04\x04\x01\x14fastly:varnish/trace\
+\x04\0\x0b\x0b\x01\0\x05trace\x03\0\0\0G\x09producers\x01\x0cprocessed-by\x02\
+\x0dwit-component\x070.215.0\x10wit-bindgen-rust\x060.30.0";
 #[inline(never)]
 #[doc(hidden)]
 pub fn __link_custom_section_describing_imports() {
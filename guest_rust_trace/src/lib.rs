@@ -26,16 +26,18 @@ fn host_get_service_id(res: &types::ReqResource) -> String {
     bindings::fastly::varnish::types::ReqResource::get_service_id(res)
 }
 
-#[allow(unused)]
-fn host_try_pop() -> bool {
+fn host_try_pop() -> Option<types::ReqResource> {
     bindings::fastly::varnish::queue::try_pop(60)
 }
 
 impl Guest for Component {
     fn enter() {
         host_log("entered");
-        while host_try_pop() {
+        while let Some(req) = host_try_pop() {
             host_log("running");
+            for cmcd in host_get("cmcd-request", &req).unwrap_or_default() {
+                host_log(&String::from_utf8_lossy(&cmcd));
+            }
         }
        host_log("done");
     }
@@ -0,0 +1,292 @@
+//! Compatibility metadata for compiled guests.
+//!
+//! `host compile` writes `<output>.meta.json` next to each `.cwasm`:
+//!
+//! ```json
+//! {
+//!   "source": "/path/to/guest.wasm",
+//!   "source_sha256": "…",
+//!   "artifact_sha256": "…",
+//!   "wasmtime": "24.0.13",
+//!   "engine": "…",
+//!   "signature": { "key": "…", "ed25519": "…" }
+//! }
+//! ```
+//!
+//! Deserializing a `.cwasm` trusts it to be machine code for this exact
+//! engine, so it is checked against the metadata first. `engine` is a hash
+//! of everything that decides whether wasmtime can run the code: its
+//! version, the target, and the compiler and engine settings.
+//!
+//! `signature` is only there when the artifact was compiled with
+//! `--sign-key`; see [`crate::signing`].
+
+use std::fmt::Write as _;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error};
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wasmtime::Engine;
+
+use crate::signing::{self, TrustedKeys};
+
+/// The version of wasmtime the host was built with.
+pub const WASMTIME_VERSION: &str = env!("WASMTIME_VERSION");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactMeta {
+    /// The component the artifact was compiled from.
+    pub source: PathBuf,
+    pub source_sha256: String,
+    /// Hash of the artifact itself, so metadata left behind by an older
+    /// build isn't taken to describe a new one.
+    pub artifact_sha256: String,
+    pub wasmtime: String,
+    /// See [`engine_fingerprint`].
+    pub engine: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<ArtifactSignature>,
+}
+
+/// An Ed25519 signature over [`ArtifactMeta::signed_message`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactSignature {
+    /// The public key that made it, in hex.
+    pub key: String,
+    /// The signature itself, in hex.
+    pub ed25519: String,
+}
+
+/// Where the metadata for `artifact` lives.
+pub fn meta_path(artifact: &Path) -> PathBuf {
+    let mut path = artifact.as_os_str().to_owned();
+    path.push(".meta.json");
+    PathBuf::from(path)
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+pub(crate) fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Feeds a [`Hash`] into SHA-256, for a digest that's stable between runs.
+struct Sha256Hasher(Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        unreachable!("only the full digest is used")
+    }
+}
+
+/// Identifies the settings code compiled by `engine` depends on. Two
+/// engines with the same fingerprint can run each other's artifacts.
+pub fn engine_fingerprint(engine: &Engine) -> String {
+    let mut hasher = Sha256Hasher(Sha256::new());
+    engine.precompile_compatibility_hash().hash(&mut hasher);
+    hex(&hasher.0.finalize())
+}
+
+impl ArtifactMeta {
+    pub fn new(engine: &Engine, source: &Path, source_bytes: &[u8], artifact: &[u8]) -> Self {
+        ArtifactMeta {
+            source: source
+                .canonicalize()
+                .unwrap_or_else(|_| source.to_path_buf()),
+            source_sha256: sha256_hex(source_bytes),
+            artifact_sha256: sha256_hex(artifact),
+            wasmtime: WASMTIME_VERSION.to_string(),
+            engine: engine_fingerprint(engine),
+            signature: None,
+        }
+    }
+
+    /// What the signature covers: the artifact, the source it can be
+    /// recompiled from and the engine it was compiled for.
+    pub fn signed_message(&self) -> Vec<u8> {
+        format!(
+            "host artifact\nartifact {}\nsource {}\nwasmtime {}\nengine {}\n",
+            self.artifact_sha256, self.source_sha256, self.wasmtime, self.engine
+        )
+        .into_bytes()
+    }
+
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.signature = Some(signing::sign(key, &self.signed_message()));
+    }
+
+    /// Fails unless the metadata is signed by one of `trusted`. Together with
+    /// [`ArtifactMeta::check_artifact`] that vouches for the artifact, and
+    /// for the source it's recompiled from if it has to be.
+    pub fn check_signature(&self, trusted: &TrustedKeys) -> Result<(), Error> {
+        let Some(signature) = &self.signature else {
+            bail!("it isn't signed, and only artifacts signed by a trusted key are loaded; compile it with `host compile --sign-key`");
+        };
+        trusted.verify(&self.signed_message(), signature)?;
+        Ok(())
+    }
+
+    /// Writes the metadata for `artifact` next to it.
+    pub fn write(&self, artifact: &Path) -> Result<(), Error> {
+        let path = meta_path(artifact);
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(&path, json).with_context(|| format!("writing {}", path.display()))
+    }
+
+    /// Reads the metadata for `artifact`.
+    pub fn read(artifact: &Path) -> Result<Self, Error> {
+        let path = meta_path(artifact);
+        let json = std::fs::read_to_string(&path).with_context(|| {
+            format!(
+                "{} has no compatibility metadata at {}; compile it with `host compile`",
+                artifact.display(),
+                path.display()
+            )
+        })?;
+        serde_json::from_str(&json).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Fails unless this metadata was written for `artifact`.
+    pub fn check_artifact(&self, artifact: &[u8]) -> Result<(), Error> {
+        if sha256_hex(artifact) != self.artifact_sha256 {
+            bail!("the artifact has changed since its metadata was written; compile it again with `host compile`");
+        }
+        Ok(())
+    }
+
+    /// Why code from this artifact can't run on `engine`, if it can't.
+    pub fn engine_mismatch(&self, engine: &Engine) -> Option<String> {
+        if self.wasmtime != WASMTIME_VERSION {
+            return Some(format!(
+                "was compiled by wasmtime {} but this host uses wasmtime {WASMTIME_VERSION}",
+                self.wasmtime
+            ));
+        }
+        if self.engine != engine_fingerprint(engine) {
+            return Some(
+                "was compiled with different engine settings, such as fuel, allocation or target features"
+                    .to_string(),
+            );
+        }
+        None
+    }
+
+    /// The source component, if it's still there and unchanged since the
+    /// artifact was compiled from it.
+    pub fn unchanged_source(&self) -> Result<Vec<u8>, Error> {
+        let bytes = std::fs::read(&self.source)
+            .with_context(|| format!("its source {} can't be read", self.source.display()))?;
+        if sha256_hex(&bytes) != self.source_sha256 {
+            bail!(
+                "its source {} has changed since it was compiled",
+                self.source.display()
+            );
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(fuel: bool) -> Engine {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(fuel);
+        Engine::new(&config).unwrap()
+    }
+
+    /// Writes `source` to a fresh file and returns metadata for an artifact
+    /// compiled from it.
+    fn compiled(name: &str, engine: &Engine, source: &[u8]) -> (ArtifactMeta, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("host-artifact-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        std::fs::write(&path, source).unwrap();
+        (
+            ArtifactMeta::new(engine, &path, source, b"machine code"),
+            path,
+        )
+    }
+
+    #[test]
+    fn matching_artifact() {
+        let engine = engine(false);
+        let (meta, source) = compiled("matching.wasm", &engine, b"(component)");
+        meta.check_artifact(b"machine code").unwrap();
+        assert_eq!(meta.engine_mismatch(&engine), None);
+        assert_eq!(meta.unchanged_source().unwrap(), b"(component)");
+        std::fs::remove_file(source).unwrap();
+    }
+
+    #[test]
+    fn changed_artifact() {
+        let (meta, source) = compiled("changed-artifact.wasm", &engine(false), b"(component)");
+        let err = meta.check_artifact(b"other machine code").unwrap_err();
+        assert!(
+            err.to_string().starts_with("the artifact has changed"),
+            "{err}"
+        );
+        std::fs::remove_file(source).unwrap();
+    }
+
+    #[test]
+    fn different_engine() {
+        let (mut meta, source) = compiled("engine.wasm", &engine(false), b"(component)");
+        let why = meta.engine_mismatch(&engine(true)).unwrap();
+        assert!(why.contains("different engine settings"), "{why}");
+
+        meta.wasmtime = "1.0.0".to_string();
+        let why = meta.engine_mismatch(&engine(false)).unwrap();
+        assert!(why.contains("wasmtime 1.0.0"), "{why}");
+        std::fs::remove_file(source).unwrap();
+    }
+
+    #[test]
+    fn changed_source() {
+        let (meta, source) = compiled("source.wasm", &engine(false), b"(component)");
+        std::fs::write(&source, b"(component (core module))").unwrap();
+        let err = meta.unchanged_source().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("has changed since it was compiled"),
+            "{err}"
+        );
+
+        std::fs::remove_file(&source).unwrap();
+        let err = meta.unchanged_source().unwrap_err();
+        assert!(err.to_string().contains("can't be read"), "{err}");
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        let bytes = [0x00, 0x7f, 0xab, 0xff];
+        assert_eq!(hex(&bytes), "007fabff");
+        assert_eq!(parse_hex("007FabfF").unwrap(), bytes);
+        for bad in ["0", "zz", "é0"] {
+            assert_eq!(parse_hex(bad), None, "{bad}");
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! A C API for embedding the host in another program, such as a VMOD
+//! inside varnishd. `capi/host.h` declares it.
+//!
+//! A `host_engine` owns a tokio runtime. Loading a guest starts a worker on
+//! that runtime which keeps entering the guest; requests pushed from any
+//! thread are queued for it, and whatever it logs to endpoints whose sink is
+//! `buffer` is held until drained. Pushing and draining may happen from
+//! several threads at once; the other calls need the engine to themselves.
+//! Functions that can fail return a
+//! `HOST_*` status and, given somewhere to put it, an error message that
+//! must be released with `host_error_free`.
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Error};
+use tokio::runtime::Runtime;
+use tokio::task::JoinHandle;
+
+use crate::config::HostConfig;
+use crate::queue::{QueueSender, TrySendError};
+use crate::request::HostRequest;
+use crate::worker::WorkerOptions;
+use crate::{Host, HostBuilder};
+
+/// Bumped whenever a declaration in `capi/host.h` changes incompatibly.
+pub const HOST_API_VERSION: u32 = 2;
+
+pub const HOST_OK: c_int = 0;
+pub const HOST_ERROR: c_int = 1;
+pub const HOST_QUEUE_FULL: c_int = 2;
+pub const HOST_CLOSED: c_int = 3;
+
+/// A request header. `name` is NUL-terminated; `value` is `value_len` bytes.
+#[repr(C)]
+pub struct HostHeader {
+    pub name: *const c_char,
+    pub value: *const u8,
+    pub value_len: usize,
+}
+
+/// A guest log message, valid only for the duration of the callback it is
+/// passed to. The strings are not NUL-terminated.
+#[repr(C)]
+pub struct HostLogRecord {
+    pub timestamp_ms: u64,
+    pub endpoint: *const c_char,
+    pub endpoint_len: usize,
+    pub sid: *const c_char,
+    pub sid_len: usize,
+    pub msg: *const c_char,
+    pub msg_len: usize,
+}
+
+pub type HostLogFn = extern "C" fn(record: *const HostLogRecord, data: *mut c_void);
+
+pub struct HostEngine {
+    runtime: Runtime,
+    config: HostConfig,
+    guest: Option<Guest>,
+}
+
+/// A loaded guest and the worker entering it.
+struct Guest {
+    host: Host,
+    sender: Option<QueueSender>,
+    worker: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl HostEngine {
+    fn load(&mut self, path: &Path) -> Result<(), Error> {
+        if self.guest.is_some() {
+            bail!("a guest is already loaded");
+        }
+        let builder = HostBuilder::new().config(self.config.clone());
+        let host = self.runtime.block_on(builder.build(path))?;
+        let sender = host.sender();
+        let worker = {
+            let host = host.clone();
+            self.runtime
+                .spawn(async move { host.run_workers(&WorkerOptions::default()).await })
+        };
+        self.guest = Some(Guest {
+            host,
+            sender,
+            worker: Some(worker),
+        });
+        Ok(())
+    }
+
+    /// Closes the queue and waits for the guest to finish with what's in it.
+    fn close(&mut self) -> Result<(), Error> {
+        let Some(guest) = &mut self.guest else {
+            return Ok(());
+        };
+        guest.sender = None;
+        guest.host.close_queue();
+        let Some(worker) = guest.worker.take() else {
+            return Ok(());
+        };
+        let host = guest.host.clone();
+        self.runtime.block_on(async move {
+            worker.await.context("guest worker panicked")??;
+            host.finish().await?;
+            Ok(())
+        })
+    }
+}
+
+/// Runs `f`, turning errors and panics into `HOST_ERROR` and a message.
+fn guard(error: *mut *mut c_char, f: impl FnOnce() -> Result<c_int, Error>) -> c_int {
+    let result = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("the host panicked")),
+    };
+    match result {
+        Ok(status) => status,
+        Err(e) => {
+            set_error(error, e);
+            HOST_ERROR
+        }
+    }
+}
+
+fn set_error(error: *mut *mut c_char, e: Error) {
+    if error.is_null() {
+        return;
+    }
+    let message = format!("{e:#}").replace('\0', " ");
+    let message = CString::new(message).expect("NULs were replaced");
+    unsafe { *error = message.into_raw() };
+}
+
+/// Borrows a NUL-terminated UTF-8 argument.
+unsafe fn arg<'a>(s: *const c_char, what: &str) -> Result<&'a str, Error> {
+    if s.is_null() {
+        bail!("{what} is NULL");
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .with_context(|| format!("{what} is not UTF-8"))
+}
+
+#[no_mangle]
+pub extern "C" fn host_api_version() -> u32 {
+    HOST_API_VERSION
+}
+
+/// Creates an engine from a TOML host config, or the defaults if `config`
+/// is NULL. Returns NULL on failure.
+///
+/// # Safety
+///
+/// `config` must be NULL or a NUL-terminated string, and `error` NULL or
+/// valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn host_engine_new(
+    config: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut HostEngine {
+    let mut engine = None;
+    guard(error, || {
+        let config = if config.is_null() {
+            HostConfig::default()
+        } else {
+            HostConfig::from_toml(arg(config, "config")?)?
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("host-runtime")
+            .build()
+            .context("starting runtime")?;
+        engine = Some(Box::new(HostEngine {
+            runtime,
+            config,
+            guest: None,
+        }));
+        Ok(HOST_OK)
+    });
+    engine.map_or(std::ptr::null_mut(), Box::into_raw)
+}
+
+/// Loads a guest, a `.cwasm` compiled with the same engine settings or a
+/// component, and starts entering it.
+///
+/// # Safety
+///
+/// `engine` must come from `host_engine_new`, `path` must be a
+/// NUL-terminated string and `error` NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn host_engine_load(
+    engine: *mut HostEngine,
+    path: *const c_char,
+    error: *mut *mut c_char,
+) -> c_int {
+    guard(error, || {
+        let engine = engine.as_mut().ok_or_else(|| anyhow!("engine is NULL"))?;
+        engine.load(Path::new(arg(path, "path")?))?;
+        Ok(HOST_OK)
+    })
+}
+
+/// Queues a request for the guest without waiting. Returns
+/// `HOST_QUEUE_FULL` if there's no room and `HOST_CLOSED` once the engine
+/// has been closed.
+///
+/// # Safety
+///
+/// `engine` must come from `host_engine_new`; `method`, `url` and
+/// `service_id` must be NUL-terminated strings; `headers` must point to
+/// `n_headers` valid headers; `error` must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn host_engine_push(
+    engine: *mut HostEngine,
+    method: *const c_char,
+    url: *const c_char,
+    headers: *const HostHeader,
+    n_headers: usize,
+    service_id: *const c_char,
+    error: *mut *mut c_char,
+) -> c_int {
+    guard(error, || {
+        let engine = engine.as_ref().ok_or_else(|| anyhow!("engine is NULL"))?;
+        let headers = match n_headers {
+            0 => &[][..],
+            n if headers.is_null() => bail!("headers is NULL but n_headers is {n}"),
+            n => std::slice::from_raw_parts(headers, n),
+        };
+        let req = HostRequest {
+            method: arg(method, "method")?.to_string(),
+            url: arg(url, "url")?.to_string(),
+            headers: headers
+                .iter()
+                .map(|h| {
+                    let value = match h.value_len {
+                        0 => &[][..],
+                        _ if h.value.is_null() => bail!("header value is NULL"),
+                        len => std::slice::from_raw_parts(h.value, len),
+                    };
+                    Ok((arg(h.name, "header name")?.to_string(), value.to_vec()))
+                })
+                .collect::<Result<_, Error>>()?,
+            service_id: arg(service_id, "service_id")?.to_string(),
+            timestamp: None,
+        };
+        let guest = engine.guest.as_ref().context("no guest loaded")?;
+        let Some(sender) = &guest.sender else {
+            return Ok(HOST_CLOSED);
+        };
+        match sender.try_send(req) {
+            Ok(()) => Ok(HOST_OK),
+            Err(TrySendError::Full(_)) => Ok(HOST_QUEUE_FULL),
+            Err(TrySendError::Closed(_)) => Ok(HOST_CLOSED),
+        }
+    })
+}
+
+/// Passes each message logged to a `buffer` sink since the last drain to
+/// `callback`, oldest first, and returns how many there were. A NULL
+/// `callback` drains nothing.
+///
+/// # Safety
+///
+/// `engine` must come from `host_engine_new`.
+#[no_mangle]
+pub unsafe extern "C" fn host_engine_drain_logs(
+    engine: *mut HostEngine,
+    callback: Option<HostLogFn>,
+    data: *mut c_void,
+) -> usize {
+    let Some(callback) = callback else {
+        return 0;
+    };
+    let Some(guest) = engine.as_ref().and_then(|e| e.guest.as_ref()) else {
+        return 0;
+    };
+    // There's no error to return here, so a panic, such as a poisoned lock,
+    // counts as nothing to drain rather than unwinding into the caller.
+    let Ok(records) = catch_unwind(AssertUnwindSafe(|| guest.host.drain_logs())) else {
+        return 0;
+    };
+    for r in &records {
+        let record = HostLogRecord {
+            timestamp_ms: r.timestamp_ms as u64,
+            endpoint: r.endpoint.as_ptr().cast(),
+            endpoint_len: r.endpoint.len(),
+            sid: r.sid.as_ptr().cast(),
+            sid_len: r.sid.len(),
+            msg: r.msg.as_ptr().cast(),
+            msg_len: r.msg.len(),
+        };
+        callback(&record, data);
+    }
+    records.len()
+}
+
+/// Stops accepting requests, waits for the guest to handle the ones already
+/// queued and flushes the sinks. Logs can still be drained afterwards.
+///
+/// # Safety
+///
+/// `engine` must come from `host_engine_new` and `error` be NULL or valid
+/// for writes.
+#[no_mangle]
+pub unsafe extern "C" fn host_engine_close(
+    engine: *mut HostEngine,
+    error: *mut *mut c_char,
+) -> c_int {
+    guard(error, || {
+        let engine = engine.as_mut().ok_or_else(|| anyhow!("engine is NULL"))?;
+        engine.close()?;
+        Ok(HOST_OK)
+    })
+}
+
+/// Closes the engine if that hasn't been done and releases it. The engine
+/// is released even if closing it fails.
+///
+/// # Safety
+///
+/// `engine` must be NULL or come from `host_engine_new`, and not be used
+/// again. `error` must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn host_engine_free(
+    engine: *mut HostEngine,
+    error: *mut *mut c_char,
+) -> c_int {
+    if engine.is_null() {
+        return HOST_OK;
+    }
+    let mut engine = Box::from_raw(engine);
+    guard(error, move || {
+        engine.close().context("closing host engine")?;
+        Ok(HOST_OK)
+    })
+}
+
+/// Releases an error message returned through an `error` argument.
+///
+/// # Safety
+///
+/// `error` must be NULL or a message from this library, not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn host_error_free(error: *mut c_char) {
+    if !error.is_null() {
+        drop(CString::from_raw(error));
+    }
+}
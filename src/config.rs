@@ -0,0 +1,387 @@
+//! Host settings read from a TOML file given with `--config`.
+//!
+//! Every table and key is optional; anything left out keeps the built-in
+//! default. Options given on the command line take precedence over the
+//! file. Unknown keys are rejected so typos don't silently fall back to a
+//! default.
+//!
+//! ```toml
+//! [engine]
+//! allocation = "pooling"
+//! coredump_dir = "traps"
+//! profile = "guest-profile.json"
+//! trusted_keys = ["keys/release.pub"]
+//!
+//! [fuel]
+//! budget = 10000000
+//!
+//! [limits]
+//! memory_bytes = 1048576
+//! table_elements = 10000
+//!
+//! [pooling]
+//! total_memories = 100
+//! max_memory_size = 2097152
+//!
+//! [epoch]
+//! tick_ms = 10
+//! deadline_ticks = 10
+//! mode = "yield"
+//! cpu_budget_ms = 100
+//!
+//! [timeouts]
+//! enter_ms = 30000
+//! max_pop_wait_ms = 1000
+//! shutdown_grace_ms = 10000
+//!
+//! [queue]
+//! capacity = 1024
+//!
+//! [sinks]
+//! cmcd = "stdout"
+//! errors = "file:errors.ndjson"
+//!
+//! [wasi]
+//! inherit_stdio = true
+//! env = { RUST_LOG = "debug" }
+//! preopens = { "/data" = "./fixtures" }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Error};
+use serde::Deserialize;
+
+use crate::epoch::{EpochMode, EPOCH_DEADLINE, EPOCH_TICK};
+use crate::sink::SinkSpec;
+
+/// Wasm pages are 64KiB; linear memory limits have to be a whole number of
+/// them.
+const WASM_PAGE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostConfig {
+    pub engine: EngineConfig,
+    pub fuel: FuelConfig,
+    pub limits: LimitsConfig,
+    pub pooling: PoolingConfig,
+    pub epoch: EpochConfig,
+    pub timeouts: TimeoutConfig,
+    pub queue: QueueConfig,
+    /// Sink for each guest endpoint, written the same way as `--sink`.
+    pub sinks: BTreeMap<String, SinkSpec>,
+    pub wasi: WasiConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    pub allocation: AllocationStrategy,
+    /// Where to write a wasm core dump and JSON trap report each time the
+    /// guest traps. Core dumps are only captured when this is set.
+    pub coredump_dir: Option<PathBuf>,
+    /// Where to write a profile of the guest, sampled on every epoch tick,
+    /// for <https://profiler.firefox.com/>.
+    pub profile: Option<PathBuf>,
+    /// Public keys a `.cwasm` has to be signed with before it's loaded. Any
+    /// one of them will do. Signatures aren't checked when this is empty.
+    pub trusted_keys: Vec<PathBuf>,
+}
+
+/// How the engine finds memory for new instances.
+#[derive(clap::ValueEnum, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AllocationStrategy {
+    /// Reserve slots for every instance up front, sized by `[pooling]`.
+    /// Instantiation is cheap but each engine holds a lot of address space.
+    #[default]
+    Pooling,
+    /// Map memory as each instance is created. Slower to instantiate, but an
+    /// idle engine reserves nothing, so many can share a process.
+    OnDemand,
+}
+
+impl fmt::Display for AllocationStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AllocationStrategy::Pooling => "pooling",
+            AllocationStrategy::OnDemand => "on-demand",
+        })
+    }
+}
+
+/// Deterministic accounting of guest work, as an alternative to the
+/// wall-clock epoch budget. Changes the generated code, so a precompiled
+/// guest has to be compiled with the same setting.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FuelConfig {
+    /// Fuel the guest gets for each queued request, topped up every time it
+    /// pops. Fuel metering is off when unset.
+    pub budget: Option<u64>,
+}
+
+/// Limits enforced on each guest, on top of whatever the allocator imposes.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Bytes of linear memory a guest may use across all of its memories.
+    pub memory_bytes: Option<usize>,
+    /// Elements any one of a guest's tables may grow to.
+    pub table_elements: Option<u32>,
+}
+
+/// Limits for the pooling instance allocator. See `make_pooling_config`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolingConfig {
+    /// Bytes of instance state each core instance may use.
+    pub max_core_instance_size: usize,
+    /// Linear memories in the pool. Each one reserves virtual memory.
+    pub total_memories: u32,
+    /// Bytes of linear memory each instance may grow to.
+    pub max_memory_size: usize,
+    /// Elements each table may hold.
+    pub table_elements: u32,
+    /// Slots kept warm for reuse by later instantiations.
+    pub max_unused_warm_slots: u32,
+    /// Core instances in the pool. A component can take several.
+    pub total_core_instances: u32,
+    /// Component instances in the pool, which bounds how many workers can
+    /// have the guest instantiated at once.
+    pub total_component_instances: u32,
+}
+
+impl Default for PoolingConfig {
+    fn default() -> Self {
+        const MB: usize = 1 << 20;
+        PoolingConfig {
+            max_core_instance_size: MB,
+            total_memories: 100,
+            max_memory_size: 2 * MB,
+            table_elements: 98765,
+            max_unused_warm_slots: 10,
+            total_core_instances: 100,
+            total_component_instances: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EpochConfig {
+    /// How often the engine's epoch is incremented, in milliseconds.
+    pub tick_ms: u64,
+    /// Most ticks the guest runs for before the deadline callback is
+    /// consulted.
+    pub deadline_ticks: u64,
+    pub mode: EpochMode,
+    /// Milliseconds the guest may execute between pops before it is trapped.
+    pub cpu_budget_ms: u64,
+}
+
+impl Default for EpochConfig {
+    fn default() -> Self {
+        EpochConfig {
+            tick_ms: EPOCH_TICK.as_millis() as u64,
+            deadline_ticks: EPOCH_DEADLINE,
+            mode: EpochMode::default(),
+            cpu_budget_ms: 100,
+        }
+    }
+}
+
+impl EpochConfig {
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms)
+    }
+
+    pub fn cpu_budget(&self) -> Duration {
+        Duration::from_millis(self.cpu_budget_ms)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Longest a single call to `enter` may take, in milliseconds, including
+    /// time spent blocked in the host. Unlimited when unset.
+    pub enter_ms: Option<u64>,
+    /// Longest `try-pop` may block, in milliseconds, whatever timeout the
+    /// guest asks for.
+    pub max_pop_wait_ms: Option<u64>,
+    /// After SIGINT or SIGTERM, how long guests get to finish the requests
+    /// already queued before they are killed, in milliseconds.
+    pub shutdown_grace_ms: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            enter_ms: None,
+            max_pop_wait_ms: None,
+            shutdown_grace_ms: 10_000,
+        }
+    }
+}
+
+impl TimeoutConfig {
+    pub fn enter(&self) -> Option<Duration> {
+        self.enter_ms.map(Duration::from_millis)
+    }
+
+    pub fn max_pop_wait(&self) -> Option<Duration> {
+        self.max_pop_wait_ms.map(Duration::from_millis)
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        Duration::from_millis(self.shutdown_grace_ms)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Requests the queue holds before producers have to wait.
+    pub capacity: usize,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig { capacity: 1024 }
+    }
+}
+
+/// What the guest sees of the host through WASI.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasiConfig {
+    /// Connect the guest's stdin, stdout and stderr to the host's.
+    pub inherit_stdio: bool,
+    /// Pass the host's environment through to the guest.
+    pub inherit_env: bool,
+    /// Extra environment variables, set after any inherited ones.
+    pub env: BTreeMap<String, String>,
+    pub args: Vec<String>,
+    /// Host directories the guest may read, keyed by the path the guest
+    /// sees them at.
+    pub preopens: BTreeMap<String, PathBuf>,
+}
+
+impl HostConfig {
+    /// Reads and validates a config file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let config: HostConfig =
+            toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("invalid config in {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Parses and validates a config held in memory.
+    pub fn from_toml(text: &str) -> Result<Self, Error> {
+        let config: HostConfig = toml::from_str(text).context("parsing config")?;
+        config.validate().context("invalid config")?;
+        Ok(config)
+    }
+
+    /// Checks the values serde can't. Errors name the offending key.
+    pub fn validate(&self) -> Result<(), Error> {
+        let p = &self.pooling;
+        if p.total_memories == 0 {
+            bail!("`pooling.total_memories` must be at least 1");
+        }
+        if p.total_core_instances == 0 {
+            bail!("`pooling.total_core_instances` must be at least 1");
+        }
+        if p.total_component_instances == 0 {
+            bail!("`pooling.total_component_instances` must be at least 1");
+        }
+        if !p.max_memory_size.is_multiple_of(WASM_PAGE_SIZE) {
+            bail!(
+                "`pooling.max_memory_size` must be a multiple of the {WASM_PAGE_SIZE} byte wasm page size, got {}",
+                p.max_memory_size
+            );
+        }
+
+        let e = &self.epoch;
+        if e.tick_ms == 0 {
+            bail!("`epoch.tick_ms` must be greater than zero");
+        }
+        if e.deadline_ticks == 0 {
+            bail!("`epoch.deadline_ticks` must be greater than zero");
+        }
+        // Both fitting in a u32 keeps the longest slice the guest can be
+        // granted, tick times ticks, well within a `Duration`.
+        if e.tick_ms > u64::from(u32::MAX) {
+            bail!(
+                "`epoch.tick_ms` must be at most {}, got {}",
+                u32::MAX,
+                e.tick_ms
+            );
+        }
+        if e.deadline_ticks > u64::from(u32::MAX) {
+            bail!(
+                "`epoch.deadline_ticks` must be at most {}, got {}",
+                u32::MAX,
+                e.deadline_ticks
+            );
+        }
+        if e.cpu_budget_ms == 0 {
+            bail!("`epoch.cpu_budget_ms` must be greater than zero");
+        }
+
+        if self.limits.memory_bytes == Some(0) {
+            bail!("`limits.memory_bytes` must be greater than zero");
+        }
+        if self.fuel.budget == Some(0) {
+            bail!("`fuel.budget` must be greater than zero");
+        }
+        if self.timeouts.enter_ms == Some(0) {
+            bail!("`timeouts.enter_ms` must be greater than zero");
+        }
+        if self.queue.capacity == 0 {
+            bail!("`queue.capacity` must be at least 1");
+        }
+
+        for (guest, host) in &self.wasi.preopens {
+            if !host.is_dir() {
+                bail!(
+                    "`wasi.preopens.\"{guest}\"`: {} is not a directory",
+                    host.display()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn epoch_error(tick_ms: u64, deadline_ticks: u64) -> String {
+        let text = format!("[epoch]\ntick_ms = {tick_ms}\ndeadline_ticks = {deadline_ticks}\n");
+        let err = HostConfig::from_toml(&text).unwrap_err();
+        format!("{err:#}")
+    }
+
+    #[test]
+    fn epoch_bounds() {
+        assert!(HostConfig::from_toml(
+            "[epoch]\ntick_ms = 4294967295\ndeadline_ticks = 4294967295\n"
+        )
+        .is_ok());
+        assert!(epoch_error(0, 10).contains("`epoch.tick_ms` must be greater than zero"));
+        assert!(epoch_error(10, 0).contains("`epoch.deadline_ticks` must be greater than zero"));
+        assert!(epoch_error(10, 1 << 32).contains("`epoch.deadline_ticks` must be at most"));
+        assert!(epoch_error(i64::MAX as u64, 10).contains("`epoch.tick_ms` must be at most"));
+    }
+}
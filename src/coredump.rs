@@ -0,0 +1,141 @@
+//! Writes wasm core dumps in the tool-conventions format.
+//!
+//! `WasmCoreDump::serialize` only knows about core instances created directly
+//! in the store, so it can't encode a dump of a component guest: the stack
+//! refers to modules the store has no instance of. This encodes the same
+//! information from the dump's public parts instead, giving each module its
+//! own instance. Wasmtime doesn't record which of a component's core
+//! instances owns which memory, so every instance is given all of them, in
+//! the order the store created them.
+
+use wasmtime::{AsContextMut, Module, Mutability, Val, WasmCoreDump};
+
+/// Memory is attached in chunks with the zeroes at either end trimmed, so
+/// mostly empty memories stay small without producing too many segments.
+const CHUNK_SIZE: usize = 4096;
+
+/// Whether two handles refer to the same compiled module.
+pub fn same_module(a: &Module, b: &Module) -> bool {
+    let (a, b) = (a.text(), b.text());
+    a.as_ptr() == b.as_ptr() && a.len() == b.len()
+}
+
+/// Encodes `dump` as a core dump of a process called `name`.
+pub fn encode(dump: &WasmCoreDump, mut store: impl AsContextMut, name: &str) -> Vec<u8> {
+    let mut store = store.as_context_mut();
+    let mut core_dump = wasm_encoder::Module::new();
+    core_dump.section(&wasm_encoder::CoreDumpSection::new(name));
+
+    let mut memories = wasm_encoder::MemorySection::new();
+    let mut data = wasm_encoder::DataSection::new();
+    for mem in dump.memories() {
+        let index = memories.len();
+        let ty = mem.ty(&store);
+        memories.memory(wasm_encoder::MemoryType {
+            minimum: mem.size(&store),
+            maximum: ty.maximum(),
+            memory64: ty.is_64(),
+            shared: ty.is_shared(),
+            page_size_log2: None,
+        });
+        for (i, chunk) in mem.data(&store).chunks(CHUNK_SIZE).enumerate() {
+            let Some(start) = chunk.iter().position(|b| *b != 0) else {
+                continue;
+            };
+            let end = chunk.iter().rposition(|b| *b != 0).unwrap() + 1;
+            let offset = (i * CHUNK_SIZE + start) as i32;
+            data.active(
+                index,
+                &wasm_encoder::ConstExpr::i32_const(offset),
+                chunk[start..end].iter().copied(),
+            );
+        }
+    }
+    core_dump.section(&memories);
+
+    let mut globals = wasm_encoder::GlobalSection::new();
+    for global in dump.globals() {
+        let ty = global.ty(&store);
+        // References are recorded as null, so only their top type matters.
+        let (val_type, init) = match global.get(&mut store) {
+            Val::I32(x) => (
+                wasm_encoder::ValType::I32,
+                wasm_encoder::ConstExpr::i32_const(x),
+            ),
+            Val::I64(x) => (
+                wasm_encoder::ValType::I64,
+                wasm_encoder::ConstExpr::i64_const(x),
+            ),
+            Val::F32(x) => (
+                wasm_encoder::ValType::F32,
+                wasm_encoder::ConstExpr::f32_const(f32::from_bits(x)),
+            ),
+            Val::F64(x) => (
+                wasm_encoder::ValType::F64,
+                wasm_encoder::ConstExpr::f64_const(f64::from_bits(x)),
+            ),
+            Val::V128(x) => (
+                wasm_encoder::ValType::V128,
+                wasm_encoder::ConstExpr::v128_const(x.as_u128() as i128),
+            ),
+            Val::ExternRef(_) => (
+                wasm_encoder::ValType::EXTERNREF,
+                wasm_encoder::ConstExpr::ref_null(wasm_encoder::HeapType::EXTERN),
+            ),
+            Val::AnyRef(_) => (
+                wasm_encoder::ValType::Ref(wasm_encoder::RefType::ANYREF),
+                wasm_encoder::ConstExpr::ref_null(wasm_encoder::HeapType::ANY),
+            ),
+            Val::FuncRef(_) => (
+                wasm_encoder::ValType::FUNCREF,
+                wasm_encoder::ConstExpr::ref_null(wasm_encoder::HeapType::FUNC),
+            ),
+        };
+        globals.global(
+            wasm_encoder::GlobalType {
+                val_type,
+                mutable: matches!(ty.mutability(), Mutability::Var),
+                shared: false,
+            },
+            &init,
+        );
+    }
+    core_dump.section(&globals);
+    core_dump.section(&data);
+
+    let mut modules = wasm_encoder::CoreDumpModulesSection::new();
+    let mut instances = wasm_encoder::CoreDumpInstancesSection::new();
+    let all_memories: Vec<u32> = (0..dump.memories().len() as u32).collect();
+    let all_globals: Vec<u32> = (0..dump.globals().len() as u32).collect();
+    for (i, module) in dump.modules().iter().enumerate() {
+        match module.name() {
+            Some(name) => modules.module(name),
+            None => modules.module(format!("<anonymous-module-{i}>")),
+        };
+        instances.instance(
+            i as u32,
+            all_memories.iter().copied(),
+            all_globals.iter().copied(),
+        );
+    }
+    core_dump.section(&modules);
+    core_dump.section(&instances);
+
+    let mut stack = wasm_encoder::CoreDumpStackSection::new("main");
+    for frame in dump.frames() {
+        let instance = dump
+            .modules()
+            .iter()
+            .position(|m| same_module(m, frame.module()))
+            .unwrap_or(0) as u32;
+        let offset = frame
+            .func_offset()
+            .and_then(|o| u32::try_from(o).ok())
+            .unwrap_or(0);
+        // Locals and the operand stack aren't recoverable.
+        stack.frame(instance, frame.func_index(), offset, [], []);
+    }
+    core_dump.section(&stack);
+
+    core_dump.finish()
+}
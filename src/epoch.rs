@@ -0,0 +1,285 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use wasmtime::{Engine, Trap, UpdateDeadline};
+
+use crate::config::EpochConfig;
+
+/// How often the engine's epoch is incremented unless configured otherwise.
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Epoch ticks the guest runs for before the deadline callback is consulted,
+/// unless configured otherwise.
+pub const EPOCH_DEADLINE: u64 = 10;
+
+/// What happens when a guest reaches its epoch deadline with CPU budget
+/// left over.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EpochMode {
+    /// Keep running on the same task until the budget is spent.
+    #[default]
+    Continue,
+    /// Yield to the async executor and pick up again later, the same as
+    /// `Store::epoch_deadline_async_yield_and_update`. Keeps long `enter`
+    /// loops from starving other tasks.
+    Yield,
+}
+
+/// Tracks how long the guest has been executing since it last returned from
+/// `try-pop`, and traps it once that exceeds the CPU budget.
+///
+/// Time spent blocked in the host or parked after a yield isn't counted.
+pub struct EpochBudget {
+    mode: EpochMode,
+    budget: Duration,
+    tick: Duration,
+    /// Most ticks granted at once.
+    max_ticks: u64,
+    /// Guest execution since the last pop.
+    running: Duration,
+    last_pop: Instant,
+    last_deadline: Instant,
+    /// Length of the slice granted at the last deadline.
+    granted: Duration,
+}
+
+impl EpochBudget {
+    pub fn new(config: &EpochConfig) -> Self {
+        let now = Instant::now();
+        let tick = config.tick();
+        EpochBudget {
+            mode: config.mode,
+            budget: config.cpu_budget(),
+            tick,
+            max_ticks: config.deadline_ticks,
+            running: Duration::ZERO,
+            last_pop: now,
+            last_deadline: now,
+            granted: tick.saturating_mul(u32::try_from(config.deadline_ticks).unwrap_or(u32::MAX)),
+        }
+    }
+
+    /// Starts the budget over. Called whenever `try-pop` hands control back
+    /// to the guest.
+    pub fn popped(&mut self) {
+        self.running = Duration::ZERO;
+        self.last_pop = Instant::now();
+    }
+
+    /// Decides what to do when the store's epoch deadline is reached.
+    pub fn deadline_reached(&mut self) -> wasmtime::Result<UpdateDeadline> {
+        let now = Instant::now();
+        // A new deadline is only set once the guest resumes, so if nothing
+        // else happened the guest ran for about the slice it was granted. If
+        // it popped in the meantime, only what came after the pop counts.
+        let ran = if self.last_pop > self.last_deadline {
+            now - self.last_pop
+        } else {
+            self.granted.min(now - self.last_deadline)
+        };
+        self.running += ran;
+        self.last_deadline = now;
+
+        if self.running >= self.budget {
+            return Err(Trap::Interrupt.into());
+        }
+        let left = (self.budget - self.running).as_millis() / self.tick.as_millis();
+        let ticks = (left as u64).clamp(1, self.max_ticks);
+        self.granted = self
+            .tick
+            .saturating_mul(u32::try_from(ticks).unwrap_or(u32::MAX));
+        Ok(match self.mode {
+            EpochMode::Continue => UpdateDeadline::Continue(ticks),
+            EpochMode::Yield => UpdateDeadline::Yield(ticks),
+        })
+    }
+}
+
+/// Increments an engine's epoch from a background thread every `interval`,
+/// for all of the engine's stores, until dropped.
+pub struct EpochTicker {
+    interval: Duration,
+    shared: Arc<TickerShared>,
+    handle: Option<JoinHandle<()>>,
+}
+
+struct TickerShared {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+    ticks: AtomicU64,
+}
+
+impl EpochTicker {
+    pub fn start(engine: Engine, interval: Duration) -> Self {
+        let shared = Arc::new(TickerShared {
+            stopped: Mutex::new(false),
+            wake: Condvar::new(),
+            ticks: AtomicU64::new(0),
+        });
+        let handle = {
+            let shared = shared.clone();
+            std::thread::Builder::new()
+                .name("wasm-epoch-ticker".into())
+                .spawn(move || shared.run(&engine, interval))
+                .expect("can spawn thread")
+        };
+        EpochTicker {
+            interval,
+            shared,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// How many times the epoch has been incremented so far.
+    pub fn ticks(&self) -> u64 {
+        self.shared.ticks.load(Ordering::Relaxed)
+    }
+}
+
+impl TickerShared {
+    fn run(&self, engine: &Engine, interval: Duration) {
+        // Ticks are scheduled from the start so oversleeping doesn't make
+        // them drift, but ones missed entirely aren't made up.
+        let mut next = Instant::now() + interval;
+        let mut stopped = self.stopped.lock().unwrap();
+        loop {
+            let wait = next.saturating_duration_since(Instant::now());
+            stopped = self
+                .wake
+                .wait_timeout_while(stopped, wait, |stopped| !*stopped)
+                .unwrap()
+                .0;
+            if *stopped {
+                return;
+            }
+            if Instant::now() < next {
+                continue;
+            }
+            engine.increment_epoch();
+            self.ticks.fetch_add(1, Ordering::Relaxed);
+            next += interval;
+            let now = Instant::now();
+            if next < now {
+                next = now + interval;
+            }
+        }
+    }
+}
+
+impl Drop for EpochTicker {
+    /// Stops the thread straight away rather than after its current sleep.
+    fn drop(&mut self) {
+        *self.shared.stopped.lock().unwrap() = true;
+        self.shared.wake.notify_all();
+        if let Some(handle) = self.handle.take() {
+            let () = handle.join().unwrap();
+        }
+    }
+}
+
+impl fmt::Display for EpochTicker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "epoch ticked {} times every {:?}",
+            self.ticks(),
+            self.interval
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(mode: EpochMode) -> EpochBudget {
+        EpochBudget::new(&EpochConfig {
+            tick_ms: 10,
+            deadline_ticks: 5,
+            mode,
+            cpu_budget_ms: 100,
+        })
+    }
+
+    /// Pretends the guest ran for `ran` since the last deadline or pop.
+    fn run_for(b: &mut EpochBudget, ran: Duration) {
+        b.last_deadline = Instant::now() - ran;
+        b.last_pop = b.last_deadline - Duration::from_millis(1);
+    }
+
+    fn ticks(update: wasmtime::Result<UpdateDeadline>) -> u64 {
+        match update.unwrap() {
+            UpdateDeadline::Continue(ticks) | UpdateDeadline::Yield(ticks) => ticks,
+        }
+    }
+
+    #[test]
+    fn mode_picks_the_update() {
+        let mut b = budget(EpochMode::Continue);
+        assert!(matches!(
+            b.deadline_reached(),
+            Ok(UpdateDeadline::Continue(_))
+        ));
+        let mut b = budget(EpochMode::Yield);
+        assert!(matches!(b.deadline_reached(), Ok(UpdateDeadline::Yield(_))));
+    }
+
+    #[test]
+    fn grants_what_is_left_of_the_budget() {
+        let mut b = budget(EpochMode::Continue);
+        run_for(&mut b, Duration::from_millis(50));
+        assert_eq!(ticks(b.deadline_reached()), 5);
+        assert_eq!(b.granted, Duration::from_millis(50));
+
+        run_for(&mut b, Duration::from_millis(25));
+        assert_eq!(ticks(b.deadline_reached()), 2);
+        assert_eq!(b.granted, Duration::from_millis(20));
+
+        // Less than a tick left still grants one.
+        run_for(&mut b, Duration::from_millis(15));
+        assert_eq!(ticks(b.deadline_reached()), 1);
+    }
+
+    #[test]
+    fn traps_once_the_budget_is_spent() {
+        let mut b = budget(EpochMode::Yield);
+        run_for(&mut b, Duration::from_millis(50));
+        b.deadline_reached().unwrap();
+        run_for(&mut b, Duration::from_millis(50));
+        let Err(err) = b.deadline_reached() else {
+            panic!("expected a trap");
+        };
+        assert_eq!(err.downcast_ref::<Trap>(), Some(&Trap::Interrupt));
+    }
+
+    #[test]
+    fn time_parked_after_a_deadline_counts_as_the_slice() {
+        let mut b = budget(EpochMode::Yield);
+        // Far longer than the slice granted, as if the guest had been parked
+        // after yielding.
+        run_for(&mut b, Duration::from_secs(1));
+        b.deadline_reached().unwrap();
+        assert_eq!(b.running, Duration::from_millis(50));
+    }
+
+    #[test]
+    fn popping_starts_the_budget_over() {
+        let mut b = budget(EpochMode::Continue);
+        run_for(&mut b, Duration::from_millis(90));
+        b.deadline_reached().unwrap();
+        b.last_deadline -= Duration::from_millis(50);
+        b.popped();
+        assert_eq!(b.running, Duration::ZERO);
+        // Only what ran since the pop counts, not the whole slice.
+        assert_eq!(ticks(b.deadline_reached()), 5);
+        assert!(b.running < Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,160 @@
+//! Diagnostics the host reports while it runs.
+//!
+//! The library never writes to stderr itself. Whatever it has to say, from
+//! trap reports to sinks falling behind, is a [`HostEvent`] handed to the
+//! [`Events`] handler given to [`HostBuilder::events`]. The `host` binary
+//! prints them; an embedder can log, count or drop them.
+//!
+//! [`HostBuilder::events`]: crate::HostBuilder::events
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Error;
+
+use crate::trap::TrapReport;
+
+/// Something that happened while the host was running.
+#[derive(Debug)]
+pub enum HostEvent {
+    /// A call into the guest trapped or failed.
+    Trapped(TrapReport),
+    /// The core dump for a trap couldn't be written.
+    CoredumpFailed(Error),
+    /// A worker couldn't instantiate the guest. It tries again after
+    /// `retry_in`, unless the host is being killed.
+    InstantiateFailed {
+        error: Error,
+        retry_in: Option<Duration>,
+    },
+    /// A worker's guest trapped and is restarted after `retry_in`.
+    Restarting { retry_in: Duration },
+    /// A worker's guest returned straight away without taking a request, and
+    /// is entered again after `retry_in`.
+    Idle { retry_in: Duration },
+    /// More workers were asked for than the instance pool can hold at once.
+    PoolTooSmall {
+        workers: usize,
+        component_instances: u32,
+    },
+    /// The shutdown grace period ran out; running guests are being killed.
+    GraceExpired(Duration),
+    /// The guest was reloaded. `recompiled` says why, if it had to be
+    /// compiled from its source.
+    Reloaded {
+        path: PathBuf,
+        version: u64,
+        recompiled: Option<String>,
+    },
+    /// Reloading failed and `version` is still running.
+    ReloadFailed { version: u64, error: Error },
+    /// The first message for an endpoint without a sink. Later ones are
+    /// only counted.
+    UnknownEndpoint(String),
+    /// A remote sink fell behind and its messages are being dropped. Only
+    /// reported the first time.
+    SinkBehind(String),
+    /// A sink failed to write a message.
+    SinkFailed { endpoint: String, error: Error },
+    /// `serve` failed to accept a connection.
+    AcceptFailed(std::io::Error),
+    /// `serve` gave up on a connection.
+    ConnectionFailed { peer: SocketAddr, error: Error },
+}
+
+impl fmt::Display for HostEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostEvent::Trapped(report) => write!(f, "{report}"),
+            HostEvent::CoredumpFailed(e) => write!(f, "failed to write core dump: {e:#}"),
+            HostEvent::InstantiateFailed { error, retry_in } => {
+                write!(f, "failed to instantiate guest: {error:#}")?;
+                match retry_in {
+                    Some(retry_in) => write!(f, "; retrying in {retry_in:?}"),
+                    None => Ok(()),
+                }
+            }
+            HostEvent::Restarting { retry_in } => {
+                write!(f, "restarting guest in {retry_in:?}")
+            }
+            HostEvent::Idle { retry_in } => write!(
+                f,
+                "guest returned without taking a request; re-entering in {retry_in:?}"
+            ),
+            HostEvent::PoolTooSmall {
+                workers,
+                component_instances,
+            } => write!(
+                f,
+                "warning: {workers} workers but the pool only has room for {component_instances} component instances; some will fail to instantiate"
+            ),
+            HostEvent::GraceExpired(grace) => {
+                write!(f, "shutdown grace period of {grace:?} expired, killing guests")
+            }
+            HostEvent::Reloaded {
+                path,
+                version,
+                recompiled,
+            } => {
+                if let Some(why) = recompiled {
+                    writeln!(f, "{why}")?;
+                }
+                write!(
+                    f,
+                    "reloaded {}, now running version {version}",
+                    path.display()
+                )
+            }
+            HostEvent::ReloadFailed { version, error } => {
+                write!(f, "failed to reload, keeping version {version}: {error:#}")
+            }
+            HostEvent::UnknownEndpoint(endpoint) => {
+                write!(f, "rejecting guest logs for unknown endpoint {endpoint}")
+            }
+            HostEvent::SinkBehind(endpoint) => write!(
+                f,
+                "the sink for endpoint {endpoint} has fallen behind; dropping its logs until it catches up"
+            ),
+            HostEvent::SinkFailed { endpoint, error } => {
+                write!(f, "failed to write log for endpoint {endpoint}: {error:#}")
+            }
+            HostEvent::AcceptFailed(e) => write!(f, "failed to accept connection: {e}"),
+            HostEvent::ConnectionFailed { peer, error } => {
+                write!(f, "connection from {peer}: {error:#}")
+            }
+        }
+    }
+}
+
+/// Where a host's events go. Clones share the same handler. The default
+/// drops every event.
+#[derive(Clone, Default)]
+pub struct Events {
+    handler: Option<Arc<dyn Fn(HostEvent) + Send + Sync>>,
+}
+
+impl Events {
+    /// Passes every event to `handler`, from whichever thread it happens on.
+    pub fn new(handler: impl Fn(HostEvent) + Send + Sync + 'static) -> Self {
+        Events {
+            handler: Some(Arc::new(handler)),
+        }
+    }
+
+    pub fn emit(&self, event: HostEvent) {
+        if let Some(handler) = &self.handler {
+            handler(event);
+        }
+    }
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Events")
+            .field("handler", &self.handler.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::request::HostRequest;
+
+/// Fuel spent by the guest on one queued request.
+#[derive(Debug, Clone)]
+pub struct FuelUsage {
+    /// `#N METHOD URL`, numbered in the order requests were popped.
+    pub request: String,
+    pub consumed: u64,
+    /// The guest ran out of fuel while handling the request.
+    pub exhausted: bool,
+}
+
+/// Fuel consumed across a run. Clones share the same counters, so every
+/// store reports into one summary.
+#[derive(Clone, Default)]
+pub struct FuelReport {
+    inner: Arc<Mutex<FuelTotals>>,
+}
+
+#[derive(Default)]
+struct FuelTotals {
+    /// Requests handed out so far, for numbering them.
+    popped: u64,
+    requests: Vec<FuelUsage>,
+    /// Fuel spent while the guest wasn't handling a request: before its
+    /// first pop and after a pop came back empty.
+    outside: u64,
+}
+
+impl FuelReport {
+    fn next_label(&self, req: &HostRequest) -> String {
+        let mut totals = self.inner.lock().unwrap();
+        totals.popped += 1;
+        format!("#{} {} {}", totals.popped, req.method, req.url)
+    }
+
+    fn record(&self, request: Option<String>, consumed: u64, exhausted: bool) {
+        let mut totals = self.inner.lock().unwrap();
+        match request {
+            Some(request) => totals.requests.push(FuelUsage {
+                request,
+                consumed,
+                exhausted,
+            }),
+            None => totals.outside += consumed,
+        }
+    }
+}
+
+impl fmt::Display for FuelReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let totals = self.inner.lock().unwrap();
+        let total: u64 = totals.requests.iter().map(|u| u.consumed).sum();
+        write!(f, "fuel: {} requests, total {total}", totals.requests.len())?;
+        if let Some(max) = totals.requests.iter().max_by_key(|u| u.consumed) {
+            let mean = total / totals.requests.len() as u64;
+            write!(f, ", mean {mean}, max {} ({})", max.consumed, max.request)?;
+        }
+        write!(f, "; {} outside requests", totals.outside)?;
+        for usage in &totals.requests {
+            write!(f, "\n  {:>12}  {}", usage.consumed, usage.request)?;
+            if usage.exhausted {
+                write!(f, " (out of fuel)")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Charges the fuel a store burns to the request its guest is handling and
+/// refuels it for the next one, from `try-pop`.
+pub struct FuelMeter {
+    budget: u64,
+    current: Option<String>,
+    report: FuelReport,
+}
+
+impl FuelMeter {
+    pub fn new(budget: u64, report: FuelReport) -> Self {
+        FuelMeter {
+            budget,
+            current: None,
+            report,
+        }
+    }
+
+    /// Called as `try-pop` returns `req`, with the fuel left in the store.
+    /// Charges the previous request and returns the fuel to give the guest
+    /// for the next one.
+    pub fn popped(&mut self, req: Option<&HostRequest>, remaining: u64) -> u64 {
+        let next = req.map(|r| self.report.next_label(r));
+        let previous = std::mem::replace(&mut self.current, next);
+        self.report
+            .record(previous, self.budget.saturating_sub(remaining), false);
+        self.budget
+    }
+
+    /// Charges whatever the guest spent since the last pop once `enter`
+    /// returns or traps.
+    pub fn finish(&mut self, remaining: u64, exhausted: bool) {
+        let current = self.current.take();
+        self.report
+            .record(current, self.budget.saturating_sub(remaining), exhausted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(report: &FuelReport) -> Vec<(String, u64, bool)> {
+        let totals = report.inner.lock().unwrap();
+        totals
+            .requests
+            .iter()
+            .map(|u| (u.request.clone(), u.consumed, u.exhausted))
+            .collect()
+    }
+
+    fn outside(report: &FuelReport) -> u64 {
+        report.inner.lock().unwrap().outside
+    }
+
+    #[test]
+    fn charges_each_request_and_refuels_on_pop() {
+        let report = FuelReport::default();
+        let mut meter = FuelMeter::new(100, report.clone());
+
+        // Fuel spent before the first pop isn't anyone's.
+        assert_eq!(meter.popped(Some(&HostRequest::get("/a")), 90), 100);
+        assert_eq!(outside(&report), 10);
+        assert!(usage(&report).is_empty());
+
+        // Each pop charges the request before it and refuels in full.
+        assert_eq!(meter.popped(Some(&HostRequest::get("/b")), 70), 100);
+        assert_eq!(meter.popped(None, 100), 100);
+        assert_eq!(meter.popped(None, 95), 100);
+        meter.finish(98, false);
+        assert_eq!(
+            usage(&report),
+            [
+                ("#1 GET /a".to_string(), 30, false),
+                ("#2 GET /b".to_string(), 0, false)
+            ]
+        );
+        assert_eq!(outside(&report), 10 + 5 + 2);
+    }
+
+    #[test]
+    fn running_out_of_fuel() {
+        let report = FuelReport::default();
+        let mut meter = FuelMeter::new(100, report.clone());
+        meter.popped(Some(&HostRequest::get("/a")), 100);
+        meter.popped(Some(&HostRequest::get("/spin")), 40);
+        meter.finish(0, true);
+        assert_eq!(
+            usage(&report),
+            [
+                ("#1 GET /a".to_string(), 60, false),
+                ("#2 GET /spin".to_string(), 100, true)
+            ]
+        );
+
+        // Running out outside a request is still charged to no one.
+        let mut meter = FuelMeter::new(100, report.clone());
+        meter.finish(0, true);
+        assert_eq!(outside(&report), 100);
+        assert_eq!(usage(&report).len(), 2);
+
+        assert_eq!(
+            report.to_string(),
+            "fuel: 2 requests, total 160, mean 80, max 100 (#2 GET /spin); 100 outside requests\n\
+             \x20           60  #1 GET /a\n\
+             \x20          100  #2 GET /spin (out of fuel)"
+        );
+    }
+
+    #[test]
+    fn stores_share_the_numbering() {
+        let report = FuelReport::default();
+        let mut first = FuelMeter::new(10, report.clone());
+        let mut second = FuelMeter::new(10, report.clone());
+        first.popped(Some(&HostRequest::get("/a")), 10);
+        second.popped(Some(&HostRequest::get("/b")), 10);
+        second.finish(4, false);
+        first.finish(7, false);
+        assert_eq!(
+            usage(&report),
+            [
+                ("#2 GET /b".to_string(), 6, false),
+                ("#1 GET /a".to_string(), 3, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_report() {
+        assert_eq!(
+            FuelReport::default().to_string(),
+            "fuel: 0 requests, total 0; 0 outside requests"
+        );
+    }
+}
@@ -0,0 +1,928 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Error};
+use ed25519_dalek::SigningKey;
+use wasmtime::component::{Component, Linker, Resource};
+use wasmtime::{
+    Config, Engine, InstanceAllocationStrategy, PoolingAllocationConfig, Store, StoreContextMut,
+    Trap, WasmBacktraceDetails, WasmCoreDump,
+};
+use wasmtime_wasi::{DirPerms, FilePerms, ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
+
+use crate::artifact::ArtifactMeta;
+use crate::config::{AllocationStrategy, HostConfig, LimitsConfig, PoolingConfig};
+use crate::coredump;
+use crate::epoch::{EpochBudget, EpochTicker};
+use crate::event::{Events, HostEvent};
+use crate::fastly::varnish::types;
+use crate::fuel::{FuelMeter, FuelReport};
+use crate::limits::{GuestLimiter, LimitReport};
+use crate::profile::SharedProfiler;
+use crate::queue::{work_queue, QueueSender, QueueStats, WorkQueue};
+use crate::request::HostRequest;
+use crate::signing::TrustedKeys;
+use crate::sink::{EndpointSink, LogRecord, LogRouter, LogStats, SinkSpec};
+use crate::trap::{RequestSummary, TrapReport};
+use crate::worker::{run_workers, WorkerOptions};
+use crate::{Trace, TracePre};
+
+struct TraceCtx {
+    logs: LogRouter,
+    queue: WorkQueue,
+    epoch: EpochBudget,
+    fuel: Option<FuelMeter>,
+    max_pop_wait: Option<Duration>,
+    /// The request the guest last popped, for trap reports.
+    current: Option<RequestSummary>,
+    /// Requests handed to the guest by this store.
+    popped: u64,
+}
+
+struct Ctx {
+    table: ResourceTable,
+    wasi: WasiCtx,
+    limits: GuestLimiter,
+    varnish: TraceCtx,
+}
+
+#[async_trait::async_trait]
+impl crate::fastly::varnish::trace_log::Host for Ctx {
+    async fn log(&mut self, msg: String, endpoint: String, sid: String) -> wasmtime::Result<()> {
+        self.varnish
+            .logs
+            .log(LogRecord::new(msg, endpoint, sid))
+            .await;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl types::Host for Ctx {}
+
+#[async_trait::async_trait]
+impl crate::fastly::varnish::types::HostReqResource for Ctx {
+    async fn get_header_names(
+        &mut self,
+        res: Resource<HostRequest>,
+    ) -> wasmtime::Result<Vec<Vec<u8>>> {
+        Ok(self.table.get(&res)?.header_names())
+    }
+
+    async fn get(
+        &mut self,
+        res: Resource<HostRequest>,
+        header: String,
+    ) -> wasmtime::Result<Option<Vec<Vec<u8>>>> {
+        Ok(self.table.get(&res)?.header(&header))
+    }
+
+    async fn get_service_id(&mut self, res: Resource<HostRequest>) -> wasmtime::Result<String> {
+        Ok(self.table.get(&res)?.service_id.clone())
+    }
+
+    fn drop(&mut self, res: Resource<HostRequest>) -> wasmtime::Result<()> {
+        self.table.delete(res)?;
+        Ok(())
+    }
+}
+
+/// Defines `queue.try-pop` by hand rather than through the bindings, which
+/// don't give host functions the store. It needs the store to charge the
+/// fuel the guest burnt on the request it's done with and refuel it for the
+/// next one.
+fn add_queue_to_linker(linker: &mut Linker<Ctx>) -> Result<(), Error> {
+    linker.instance("fastly:varnish/queue")?.func_wrap_async(
+        "try-pop",
+        |mut store: StoreContextMut<'_, Ctx>, (timeout_secs,): (u64,)| {
+            Box::new(async move {
+                let ctx = &mut store.data_mut().varnish;
+                let mut timeout = Duration::from_secs(timeout_secs);
+                if let Some(max) = ctx.max_pop_wait {
+                    timeout = timeout.min(max);
+                }
+                let req = ctx.queue.pop(timeout).await;
+                ctx.epoch.popped();
+                ctx.current = req.as_ref().map(RequestSummary::from);
+                ctx.popped += u64::from(req.is_some());
+                if store.data().varnish.fuel.is_some() {
+                    let remaining = store.get_fuel()?;
+                    let meter = store.data_mut().varnish.fuel.as_mut().unwrap();
+                    let refuel = meter.popped(req.as_ref(), remaining);
+                    store.set_fuel(refuel)?;
+                }
+                match req {
+                    Some(req) => Ok((Some(store.data_mut().table.push(req)?),)),
+                    None => Ok((None,)),
+                }
+            })
+        },
+    )
+}
+
+impl WasiView for Ctx {
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+
+    fn ctx(&mut self) -> &mut WasiCtx {
+        &mut self.wasi
+    }
+}
+
+fn my_add_to_linker<T: WasiView>(l: &mut wasmtime::component::Linker<T>) -> anyhow::Result<()> {
+    let _ = l;
+
+    wasmtime_wasi::add_to_linker_async(l)?;
+    Ok(())
+}
+
+fn create_engine(host: &HostConfig) -> Result<(Engine, Linker<Ctx>), Error> {
+    let mut config = Config::default();
+    config.wasm_component_model(true);
+    config.async_support(true);
+    config.wasm_backtrace_details(WasmBacktraceDetails::Enable);
+    config.epoch_interruption(true);
+    config.consume_fuel(host.fuel.budget.is_some());
+    config.coredump_on_trap(host.engine.coredump_dir.is_some());
+
+    config.allocation_strategy(match host.engine.allocation {
+        AllocationStrategy::Pooling => {
+            InstanceAllocationStrategy::Pooling(make_pooling_config(&host.pooling))
+        }
+        AllocationStrategy::OnDemand => InstanceAllocationStrategy::OnDemand,
+    });
+
+    let engine = Engine::new(&config)?;
+
+    let mut linker: Linker<Ctx> = Linker::new(&engine);
+    crate::fastly::varnish::types::add_to_linker(&mut linker, |ctx| ctx)?;
+    crate::fastly::varnish::trace_log::add_to_linker(&mut linker, |ctx| ctx)?;
+    add_queue_to_linker(&mut linker)?;
+    my_add_to_linker(&mut linker)?;
+
+    Ok((engine, linker))
+}
+
+fn make_pooling_config(config: &PoolingConfig) -> PoolingAllocationConfig {
+    let mut pooling_allocation_config = PoolingAllocationConfig::default();
+
+    // The default of 1MiB matches Compute production
+    pooling_allocation_config.max_core_instance_size(config.max_core_instance_size);
+
+    // Core wasm programs have 1 memory
+    // This is where things get expensive. Each of these reserves virtual memory,
+    pooling_allocation_config.total_memories(config.total_memories);
+    pooling_allocation_config.max_memories_per_module(1);
+
+    // allow for up to 2MiB of linear memory by default
+    pooling_allocation_config.max_memory_size(config.max_memory_size);
+
+    // Core wasm programs have 1 table
+    pooling_allocation_config.max_tables_per_module(1);
+
+    // Some applications create a large number of functions, in particular
+    // when compiled in debug mode or applications written in swift. Every
+    // function can end up in the table
+    pooling_allocation_config.table_elements(config.table_elements);
+
+    // Maximum number of slots in the pooling allocator to keep "warm", or those
+    // to keep around to possibly satisfy an affine allocation request or an
+    // instantiation of a module previously instantiated within the pool.
+    pooling_allocation_config.max_unused_warm_slots(config.max_unused_warm_slots);
+
+    // Use a large pool, but one smaller than the default of 1000 to avoid runnign out of virtual
+    // memory space if multiple engines are spun up in a single process. We'll likely want to move
+    // to the on-demand allocator eventually for most purposes; see
+    // https://github.com/fastly/Viceroy/issues/255
+    pooling_allocation_config.total_core_instances(config.total_core_instances);
+
+    // Each worker's guest is one component instance, however many core
+    // instances it's made of.
+    pooling_allocation_config.total_component_instances(config.total_component_instances);
+    pooling_allocation_config
+}
+
+/// Size of this process's virtual address space, on platforms that make it
+/// easy to find out.
+fn address_space_size() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kb = status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmSize:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+/// Renders a byte count in the largest binary unit that keeps it above one.
+pub(crate) fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// Says how much address space creating the engine reserved, so the cost of
+/// each allocation strategy can be shown at startup.
+fn describe_reservation(host: &HostConfig, before: Option<u64>, after: Option<u64>) -> String {
+    let reserved = match (before, after) {
+        (Some(before), Some(after)) => human_bytes(after.saturating_sub(before)),
+        _ => "an unknown amount".to_string(),
+    };
+    match host.engine.allocation {
+        AllocationStrategy::Pooling => format!(
+            "pooling allocator reserved {reserved} of address space for {} memories of {} and {} core instances",
+            host.pooling.total_memories,
+            human_bytes(host.pooling.max_memory_size as u64),
+            host.pooling.total_core_instances,
+        ),
+        AllocationStrategy::OnDemand => format!(
+            "on-demand allocator reserved {reserved} of address space up front; instances reserve their own as they are created"
+        ),
+    }
+}
+
+/// Compiles a guest component ahead of time into `output`, checking that it
+/// fits the trace world, and writes its compatibility metadata alongside.
+/// The engine settings in `config` must match the ones the result is later
+/// run with. The metadata is signed with `sign_key`, if given.
+pub fn compile(
+    config: &HostConfig,
+    wasm: &Path,
+    output: &Path,
+    sign_key: Option<&SigningKey>,
+) -> Result<(), Error> {
+    let (engine, linker) = create_engine(config)?;
+    let source = std::fs::read(wasm).with_context(|| format!("reading {}", wasm.display()))?;
+    let component = Component::from_file(&engine, wasm)?;
+    let _varnish_pre = linker
+        .instantiate_pre(&component)
+        .context("conforms to Varnish world")?;
+    let serialized = component.serialize()?;
+    std::fs::write(output, &serialized).with_context(|| format!("writing {}", output.display()))?;
+    let mut meta = ArtifactMeta::new(&engine, wasm, &source, &serialized);
+    if let Some(key) = sign_key {
+        meta.sign(key);
+    }
+    meta.write(output)
+}
+
+/// Sets up a [`Host`] for a guest.
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use host::{sink::SinkSpec, HostBuilder};
+///
+/// let host = HostBuilder::new()
+///     .sink("cmcd", SinkSpec::Stdout)
+///     .build("guest.cwasm".as_ref())
+///     .await?;
+/// let sender = host.sender().unwrap();
+/// // ... queue requests through `sender`, then:
+/// host.close_queue();
+/// host.run_once().await?;
+/// host.finish().await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct HostBuilder {
+    config: HostConfig,
+    queue: Option<WorkQueue>,
+    fault: String,
+    events: Events,
+}
+
+impl HostBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the whole configuration: engine and pooling settings, epoch
+    /// and fuel budgets, timeouts, sinks, limits and WASI.
+    pub fn config(mut self, config: HostConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Routes guest logs for `endpoint` to `sink`.
+    pub fn sink(mut self, endpoint: impl Into<String>, sink: SinkSpec) -> Self {
+        self.config.sinks.insert(endpoint.into(), sink);
+        self
+    }
+
+    /// Takes requests from `queue` rather than a queue of the host's own.
+    pub fn queue(mut self, queue: WorkQueue) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    pub fn limits(mut self, limits: LimitsConfig) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    /// Passed to the guest in the `INJECT_FAULT` environment variable.
+    pub fn fault(mut self, fault: impl Into<String>) -> Self {
+        self.fault = fault.into();
+        self
+    }
+
+    /// Where the host reports traps, restarts, sink trouble and the like.
+    /// Without this they're dropped.
+    pub fn events(mut self, events: Events) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Creates the engine, loads the guest at `guest` (a component, or a
+    /// `.cwasm` from [`compile`]) and opens the sinks.
+    pub async fn build(self, guest: &Path) -> Result<Host, Error> {
+        let mut config = self.config;
+        config.validate()?;
+
+        let before = address_space_size();
+        let (engine, linker) = create_engine(&config)?;
+        let reservation = describe_reservation(&config, before, address_space_size());
+
+        let trusted = TrustedKeys::load(&config.engine.trusted_keys)?;
+        let (pre, recompiled) = load_guest(&engine, &linker, &trusted, guest)?;
+
+        let sinks: Vec<EndpointSink> = config
+            .sinks
+            .iter()
+            .map(|(endpoint, sink)| EndpointSink {
+                endpoint: endpoint.clone(),
+                sink: sink.clone(),
+            })
+            .collect();
+        let (sender, queue) = match self.queue {
+            Some(queue) => (None, queue),
+            None => {
+                let (tx, queue) = work_queue(config.queue.capacity);
+                (Some(tx), queue)
+            }
+        };
+        let tick = config.epoch.tick();
+        if config.engine.profile.is_some() {
+            // Reach the deadline callback, and so take a sample, on every tick.
+            config.epoch.deadline_ticks = 1;
+        }
+        let guest_name = guest.display().to_string();
+        let inner = HostInner {
+            engine: engine.clone(),
+            linker,
+            path: guest.to_path_buf(),
+            trusted,
+            guest: RwLock::new(Arc::new(LoadedGuest {
+                pre,
+                version: 1,
+                recompiled,
+            })),
+            reservation,
+            reloading: tokio::sync::Mutex::new(()),
+            fault: self.fault,
+            logs: LogRouter::from_specs(&sinks, self.events.clone()).await?,
+            queue,
+            fuel: FuelReport::default(),
+            limits: LimitReport::default(),
+            events: self.events,
+            traps: AtomicU64::new(0),
+            profiler: config
+                .engine
+                .profile
+                .is_some()
+                .then(|| SharedProfiler::new(&guest_name, tick)),
+            guest_name,
+            config,
+            sender: Mutex::new(sender),
+            killed: Arc::new(AtomicBool::new(false)),
+            ticker: EpochTicker::start(engine, tick),
+        };
+
+        Ok(Host {
+            inner: Arc::new(inner),
+        })
+    }
+}
+
+/// Loads the guest at `path` and links it. A `.cwasm` is read into memory
+/// rather than mapped, so a new version can be written over it while this
+/// one is running.
+///
+/// A `.cwasm` is only deserialized if its metadata says it was compiled for
+/// an engine like this one. Otherwise it's compiled again from its source,
+/// as long as that hasn't changed, and the reason is returned along with it.
+/// With `trusted` keys, it has to be signed by one of them as well.
+fn load_guest(
+    engine: &Engine,
+    linker: &Linker<Ctx>,
+    trusted: &TrustedKeys,
+    path: &Path,
+) -> Result<(TracePre<Ctx>, Option<String>), Error> {
+    let mut recompiled = None;
+    let component = if path.extension().is_some_and(|e| e == "cwasm") {
+        let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let meta = ArtifactMeta::read(path)?;
+        meta.check_artifact(&bytes)
+            .with_context(|| format!("checking {}", path.display()))?;
+        if !trusted.is_empty() {
+            meta.check_signature(trusted)
+                .with_context(|| format!("checking {}", path.display()))?;
+        }
+        match meta.engine_mismatch(engine) {
+            // SAFETY: the metadata vouches for this being code compiled by an
+            // engine configured the same way.
+            None => unsafe { Component::deserialize(engine, bytes) }?,
+            Some(why) => {
+                let source = meta.unchanged_source().with_context(|| {
+                    format!("{} {why}, and can't be recompiled", path.display())
+                })?;
+                let component = Component::new(engine, source)?;
+                recompiled = Some(format!(
+                    "{} {why}; recompiled it from {}",
+                    path.display(),
+                    meta.source.display()
+                ));
+                component
+            }
+        }
+    } else {
+        Component::from_file(engine, path)?
+    };
+
+    // TODO: load out of a pre-compiled shared object instead of compiling on
+    // demand
+    Ok((
+        TracePre::new(linker.instantiate_pre(&component)?)?,
+        recompiled,
+    ))
+}
+
+/// One version of the guest, numbered from 1 in the order they were loaded.
+struct LoadedGuest {
+    pre: TracePre<Ctx>,
+    version: u64,
+    /// Why it was compiled again from source rather than loaded as it was,
+    /// if it was.
+    recompiled: Option<String>,
+}
+
+/// A loaded guest, ready to be instantiated and entered as often as needed.
+///
+/// Clones share the same engine, guest, queue and reports, so workers on
+/// other tasks can each take one.
+#[derive(Clone)]
+pub struct Host {
+    inner: Arc<HostInner>,
+}
+
+/// Everything needed to stand up a fresh store and guest instance.
+struct HostInner {
+    engine: Engine,
+    linker: Linker<Ctx>,
+    /// Where the guest was loaded from, and is reloaded from.
+    path: PathBuf,
+    /// See [`Host::reservation`].
+    reservation: String,
+    /// Keys a reloaded `.cwasm` has to be signed with.
+    trusted: TrustedKeys,
+    /// The version new instances are created from.
+    guest: RwLock<Arc<LoadedGuest>>,
+    /// Held while a new version is being loaded.
+    reloading: tokio::sync::Mutex<()>,
+    fault: String,
+    logs: LogRouter,
+    queue: WorkQueue,
+    fuel: FuelReport,
+    limits: LimitReport,
+    events: Events,
+    /// Name of the guest file, recorded in core dumps.
+    guest_name: String,
+    /// Traps written to the core dump directory so far.
+    traps: AtomicU64,
+    profiler: Option<SharedProfiler>,
+    config: HostConfig,
+    /// The producing end of the host's own queue, when it wasn't given one.
+    sender: Mutex<Option<QueueSender>>,
+    /// Set by [`Host::kill`]; every store traps at its next epoch deadline.
+    killed: Arc<AtomicBool>,
+    /// Shared by every store. Stops once the host and every instance of it
+    /// are dropped.
+    ticker: EpochTicker,
+}
+
+impl Host {
+    pub fn engine(&self) -> &Engine {
+        &self.inner.engine
+    }
+
+    pub fn config(&self) -> &HostConfig {
+        &self.inner.config
+    }
+
+    pub fn ticker(&self) -> &EpochTicker {
+        &self.inner.ticker
+    }
+
+    pub fn queue(&self) -> &WorkQueue {
+        &self.inner.queue
+    }
+
+    /// Where the host reports what happens while it runs.
+    pub fn events(&self) -> &Events {
+        &self.inner.events
+    }
+
+    /// A handle for adding requests to the host's own queue. `None` if the
+    /// builder was given a queue, or after [`Host::close_queue`].
+    pub fn sender(&self) -> Option<QueueSender> {
+        self.inner.sender.lock().unwrap().clone()
+    }
+
+    /// Drops the host's own sender, so the queue closes once every other
+    /// sender has gone too. The guest then learns there's no more work.
+    pub fn close_queue(&self) {
+        self.inner.sender.lock().unwrap().take();
+    }
+
+    /// Stops taking work: the queue refuses new requests, and once the ones
+    /// already queued are popped `try-pop` reports there's no more, so guests
+    /// can return from `enter`. Guests still running after `grace` are
+    /// killed. Must be called within a tokio runtime.
+    pub fn shutdown(&self, grace: Duration) {
+        self.inner.queue.close();
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            if let Some(inner) = inner.upgrade() {
+                inner.events.emit(HostEvent::GraceExpired(grace));
+                Host { inner }.kill();
+            }
+        });
+    }
+
+    /// Traps every running guest at its next epoch deadline and stops
+    /// workers from restarting them.
+    pub fn kill(&self) {
+        self.inner.queue.close();
+        self.inner.killed.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.inner.killed.load(Ordering::Relaxed)
+    }
+
+    /// Takes the messages logged to `buffer` sinks so far, oldest first.
+    pub fn drain_logs(&self) -> Vec<LogRecord> {
+        self.inner.logs.drain_buffer()
+    }
+
+    pub fn guest_path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// The version of the guest new instances are created from, starting at
+    /// 1 and going up with each successful [`Host::reload`].
+    pub fn version(&self) -> u64 {
+        self.inner.guest.read().unwrap().version
+    }
+
+    /// Why the current version of the guest was compiled again from its
+    /// source rather than loaded as it was, if it was.
+    pub fn recompiled(&self) -> Option<String> {
+        self.inner.guest.read().unwrap().recompiled.clone()
+    }
+
+    /// How much address space creating the engine reserved.
+    pub fn reservation(&self) -> &str {
+        &self.inner.reservation
+    }
+
+    /// Loads the guest file again, compiling it off the async threads, and
+    /// creates new instances from the new version from then on. Instances
+    /// already running keep the version they started with. If loading fails
+    /// the current version stays. Returns the new version.
+    pub async fn reload(&self) -> Result<u64, Error> {
+        let _reloading = self.inner.reloading.lock().await;
+        let inner = self.inner.clone();
+        let (pre, recompiled) = tokio::task::spawn_blocking(move || {
+            load_guest(&inner.engine, &inner.linker, &inner.trusted, &inner.path)
+        })
+        .await
+        .context("loading the guest panicked")?
+        .with_context(|| format!("reloading {}", self.inner.path.display()))?;
+        let mut guest = self.inner.guest.write().unwrap();
+        let version = guest.version + 1;
+        *guest = Arc::new(LoadedGuest {
+            pre,
+            version,
+            recompiled,
+        });
+        Ok(version)
+    }
+
+    /// Creates a fresh store and instance of the current version of the
+    /// guest.
+    pub async fn instantiate(&self) -> Result<TraceInstance, Error> {
+        let inner = &self.inner;
+        let guest = inner.guest.read().unwrap().clone();
+        let ctx = Ctx {
+            table: ResourceTable::new(),
+            wasi: inner.wasi_ctx()?,
+            limits: GuestLimiter::new(&inner.config.limits, inner.limits.clone()),
+            varnish: TraceCtx {
+                logs: inner.logs.clone(),
+                queue: inner.queue.clone(),
+                epoch: EpochBudget::new(&inner.config.epoch),
+                fuel: inner
+                    .config
+                    .fuel
+                    .budget
+                    .map(|budget| FuelMeter::new(budget, inner.fuel.clone())),
+                max_pop_wait: inner.config.timeouts.max_pop_wait(),
+                current: None,
+                popped: 0,
+            },
+        };
+        let mut store = Store::new(&inner.engine, ctx);
+        store.limiter(|ctx| &mut ctx.limits);
+        store.set_epoch_deadline(inner.config.epoch.deadline_ticks);
+        let profiler = inner.profiler.clone();
+        let killed = inner.killed.clone();
+        store.epoch_deadline_callback(move |mut ctx| {
+            if killed.load(Ordering::Relaxed) {
+                return Err(Error::from(Trap::Interrupt).context("killed while shutting down"));
+            }
+            if let Some(profiler) = &profiler {
+                profiler.sample(&ctx);
+            }
+            ctx.data_mut().varnish.epoch.deadline_reached()
+        });
+
+        let trace = match guest.pre.instantiate_async(&mut store).await {
+            Ok(trace) => trace,
+            Err(e) => match store.data_mut().limits.take_hit() {
+                Some(hit) => return Err(e.context(hit.to_string())),
+                None => return Err(e),
+            },
+        };
+        Ok(TraceInstance {
+            host: inner.clone(),
+            store,
+            trace,
+            version: guest.version,
+        })
+    }
+
+    /// Instantiates the guest and enters it once.
+    pub async fn run_once(&self) -> Result<(), Error> {
+        let mut instance = self.instantiate().await?;
+        if let Err(e) = instance.enter().await {
+            instance.report_trap(e);
+            bail!("guest trapped");
+        }
+        Ok(())
+    }
+
+    /// Keeps entering the guest from `opts.workers` workers until the queue
+    /// is closed and drained, restarting it after traps.
+    pub async fn run_workers(&self, opts: &WorkerOptions) -> Result<(), Error> {
+        let config = &self.inner.config;
+        opts.check(config)?;
+        if config.engine.allocation == AllocationStrategy::Pooling
+            && opts.workers > config.pooling.total_component_instances as usize
+        {
+            self.inner.events.emit(HostEvent::PoolTooSmall {
+                workers: opts.workers,
+                component_instances: config.pooling.total_component_instances,
+            });
+        }
+        run_workers(self, opts).await
+    }
+
+    /// Flushes the sinks, writes the profile, if any, and sums up the run.
+    pub async fn finish(&self) -> Result<RunSummary, Error> {
+        let inner = &self.inner;
+        inner.logs.flush().await?;
+        let profile = match (&inner.profiler, &inner.config.engine.profile) {
+            (Some(profiler), Some(path)) => Some(profiler.finish(path).map(|()| path.clone())),
+            _ => None,
+        };
+        Ok(RunSummary {
+            logs: inner.logs.stats(),
+            queue: inner.queue.stats(),
+            epoch: inner.ticker.to_string(),
+            limits: inner.limits.to_string(),
+            fuel: inner
+                .config
+                .fuel
+                .budget
+                .is_some()
+                .then(|| inner.fuel.to_string()),
+            profile,
+        })
+    }
+}
+
+/// What happened over a run, from [`Host::finish`]: logs that went
+/// nowhere, queue throughput, memory use, fuel and the profile, if any.
+pub struct RunSummary {
+    logs: LogStats,
+    queue: QueueStats,
+    epoch: String,
+    limits: String,
+    fuel: Option<String>,
+    /// Where the profile was written, or why it couldn't be.
+    profile: Option<Result<PathBuf, Error>>,
+}
+
+impl RunSummary {
+    pub fn logs(&self) -> &LogStats {
+        &self.logs
+    }
+
+    pub fn queue(&self) -> &QueueStats {
+        &self.queue
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.logs.is_empty() {
+            writeln!(f, "{}", self.logs)?;
+        }
+        writeln!(f, "{}", self.queue)?;
+        writeln!(f, "{}", self.epoch)?;
+        write!(f, "{}", self.limits)?;
+        if let Some(fuel) = &self.fuel {
+            write!(f, "\n{fuel}")?;
+        }
+        match &self.profile {
+            Some(Ok(path)) => write!(f, "\nwrote guest profile to {}", path.display()),
+            Some(Err(e)) => write!(f, "\nfailed to write guest profile: {e:#}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl HostInner {
+    fn wasi_ctx(&self) -> Result<WasiCtx, Error> {
+        let wasi = &self.config.wasi;
+        let mut builder = WasiCtxBuilder::new();
+        if wasi.inherit_stdio {
+            builder.inherit_stdio();
+        }
+        if wasi.inherit_env {
+            builder.inherit_env();
+        }
+        for (key, value) in &wasi.env {
+            builder.env(key, value);
+        }
+        builder.env("INJECT_FAULT", &self.fault);
+        builder.args(&wasi.args);
+        for (guest, host) in &wasi.preopens {
+            builder
+                .preopened_dir(host, guest, DirPerms::READ, FilePerms::READ)
+                .with_context(|| format!("preopening {} as {guest}", host.display()))?;
+        }
+        Ok(builder.build())
+    }
+}
+
+/// A guest instance and the store it lives in.
+pub struct TraceInstance {
+    host: Arc<HostInner>,
+    store: Store<Ctx>,
+    trace: Trace,
+    version: u64,
+}
+
+impl TraceInstance {
+    /// The version of the guest this is an instance of.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// How many requests the guest has popped over the life of this
+    /// instance.
+    pub fn popped(&self) -> u64 {
+        self.store.data().varnish.popped
+    }
+
+    /// Calls `trace-hooks.enter`, giving up once the configured enter
+    /// timeout passes. A guest that never yields to the executor can only be
+    /// stopped by its CPU budget.
+    pub async fn enter(&mut self) -> Result<(), Error> {
+        let host = &self.host;
+        let store = &mut self.store;
+        if let Some(budget) = host.config.fuel.budget {
+            store.set_fuel(budget)?;
+        }
+        store.data_mut().varnish.current = None;
+
+        let enter = self
+            .trace
+            .fastly_varnish_trace_hooks()
+            .call_enter(&mut *store);
+        let result = match host.config.timeouts.enter() {
+            Some(limit) => tokio::time::timeout(limit, enter)
+                .await
+                .map_err(|_| anyhow::anyhow!("enter timed out after {limit:?}"))
+                .and_then(|r| r),
+            None => enter.await,
+        };
+
+        let result = match result {
+            Ok(()) => {
+                store.data_mut().limits.take_hit();
+                Ok(())
+            }
+            Err(e) => {
+                let hit = store.data_mut().limits.take_hit();
+                // Timeouts and host errors end the call too, but aren't traps.
+                if e.downcast_ref::<Trap>().is_some() {
+                    host.limits.trapped(hit);
+                }
+                match hit {
+                    Some(hit) => Err(e.context(hit.to_string())),
+                    None => Err(e),
+                }
+            }
+        };
+
+        if host.config.fuel.budget.is_some() {
+            let remaining = store.get_fuel()?;
+            let exhausted = matches!(
+                result
+                    .as_ref()
+                    .err()
+                    .and_then(|e| e.root_cause().downcast_ref::<Trap>()),
+                Some(Trap::OutOfFuel)
+            );
+            store
+                .data_mut()
+                .varnish
+                .fuel
+                .as_mut()
+                .unwrap()
+                .finish(remaining, exhausted);
+        }
+        result
+    }
+
+    /// Describes a failed [`TraceInstance::enter`], reports it as a
+    /// [`HostEvent::Trapped`] and writes it to the core dump directory if
+    /// there is one.
+    pub fn report_trap(&mut self, error: Error) -> TrapReport {
+        let host = self.host.clone();
+        let mut report = TrapReport::new(&error, self.store.data().varnish.current.clone());
+        if let Some(dir) = &host.config.engine.coredump_dir {
+            if let Err(e) = self.write_coredump(dir, &error, &mut report) {
+                host.events.emit(HostEvent::CoredumpFailed(e));
+            }
+        }
+        host.events.emit(HostEvent::Trapped(report.clone()));
+        report
+    }
+
+    /// Writes `trap-<ms>-<n>.json` and, when the error carries one,
+    /// `trap-<ms>-<n>.coredump` into `dir`.
+    fn write_coredump(
+        &mut self,
+        dir: &Path,
+        error: &Error,
+        report: &mut TrapReport,
+    ) -> Result<(), Error> {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let n = self.host.traps.fetch_add(1, Ordering::Relaxed);
+        let stem = format!("trap-{}-{n}", now.as_millis());
+
+        if let Some(dump) = error.downcast_ref::<WasmCoreDump>() {
+            let name = format!("{stem}.coredump");
+            let path = dir.join(&name);
+            let bytes = coredump::encode(dump, &mut self.store, &self.host.guest_name);
+            std::fs::write(&path, bytes).with_context(|| format!("writing {}", path.display()))?;
+            report.coredump = Some(name);
+        }
+
+        let path = dir.join(format!("{stem}.json"));
+        std::fs::write(&path, report.to_json()?)
+            .with_context(|| format!("writing {}", path.display()))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,534 @@
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Error};
+use serde::Deserialize;
+
+use crate::queue::QueueSender;
+use crate::request::HostRequest;
+use crate::vsl;
+
+/// Formats requests can be recorded in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// A single raw HTTP/1.1 request.
+    Http,
+    /// `varnishlog` text output.
+    Vsl,
+    /// An HTTP archive as exported by browsers and proxies.
+    Har,
+    /// One JSON request object per line.
+    Ndjson,
+}
+
+/// A recorded request source, given on the command line as `FORMAT:PATH`.
+/// The format may be left off for files ending in `.http`, `.vsl`, `.har`,
+/// `.ndjson` or `.jsonl`.
+#[derive(Debug, Clone)]
+pub struct InputSpec {
+    pub format: InputFormat,
+    pub path: PathBuf,
+}
+
+impl FromStr for InputSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let prefixed = [
+            ("http:", InputFormat::Http),
+            ("vsl:", InputFormat::Vsl),
+            ("har:", InputFormat::Har),
+            ("ndjson:", InputFormat::Ndjson),
+        ];
+        for (prefix, format) in prefixed {
+            if let Some(path) = s.strip_prefix(prefix) {
+                return Ok(InputSpec {
+                    format,
+                    path: path.into(),
+                });
+            }
+        }
+
+        let path = Path::new(s);
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("http") => InputFormat::Http,
+            Some("vsl") => InputFormat::Vsl,
+            Some("har") => InputFormat::Har,
+            Some("ndjson" | "jsonl") => InputFormat::Ndjson,
+            _ => {
+                bail!("can't tell the format of `{s}`; prefix it with http:, vsl:, har: or ndjson:")
+            }
+        };
+        Ok(InputSpec {
+            format,
+            path: path.into(),
+        })
+    }
+}
+
+impl InputSpec {
+    /// Reads every request in the source. Requests that don't name their own
+    /// service get `service_id`.
+    pub fn load(&self, service_id: &str) -> Result<Vec<HostRequest>, Error> {
+        let file = std::fs::File::open(&self.path)
+            .with_context(|| format!("opening {}", self.path.display()))?;
+        let mut reader = std::io::BufReader::new(file);
+        let requests = match self.format {
+            InputFormat::Http => {
+                let mut raw = Vec::new();
+                std::io::Read::read_to_end(&mut reader, &mut raw)?;
+                HostRequest::parse(&raw, service_id).map(|r| vec![r])
+            }
+            InputFormat::Vsl => vsl::parse(reader, service_id),
+            InputFormat::Har => parse_har(reader, service_id),
+            InputFormat::Ndjson => parse_ndjson(reader, service_id),
+        };
+        requests.with_context(|| format!("parsing {}", self.path.display()))
+    }
+}
+
+/// How quickly recorded requests are fed to the queue.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pace {
+    /// As fast as the guest pops them.
+    Asap,
+    /// Keeping the gaps between the recorded timestamps, divided by the
+    /// factor. `1` replays in real time, `10` ten times faster.
+    Scaled(f64),
+}
+
+impl FromStr for Pace {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "asap" => Ok(Pace::Asap),
+            "realtime" => Ok(Pace::Scaled(1.0)),
+            _ => {
+                let factor: f64 = s.strip_suffix('x').unwrap_or(s).parse().map_err(|_| {
+                    anyhow!("expected asap, realtime or a speed-up like 10x, got `{s}`")
+                })?;
+                if !(factor.is_finite() && factor > 0.0) {
+                    bail!("speed-up must be a positive number, got `{s}`");
+                }
+                Ok(Pace::Scaled(factor))
+            }
+        }
+    }
+}
+
+/// Sends recorded requests to the queue in order, spaced out according to
+/// `pace`, then drops `tx` to close the queue. Requests without a timestamp
+/// go out right after the one before them.
+///
+/// The schedule is worked out before anything is sent, so a pace that would
+/// put a request further out than the clock can represent is an error here
+/// rather than a panic in the spawned feeder.
+pub fn feed(
+    requests: Vec<HostRequest>,
+    tx: QueueSender,
+    pace: Pace,
+) -> Result<impl std::future::Future<Output = ()>, Error> {
+    let start = Instant::now();
+    let schedule = schedule(&requests, pace, start)?;
+    Ok(async move {
+        for (req, at) in requests.into_iter().zip(schedule) {
+            if let Some(at) = at {
+                tokio::time::sleep_until(at.into()).await;
+            }
+            if tx.send(req).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// When each request is due, counting from `start`. `None` means as soon as
+/// the one before it has gone out.
+fn schedule(
+    requests: &[HostRequest],
+    pace: Pace,
+    start: Instant,
+) -> Result<Vec<Option<Instant>>, Error> {
+    let first = requests.iter().filter_map(|r| r.timestamp).min();
+    requests
+        .iter()
+        .enumerate()
+        .map(|(n, req)| {
+            let (Pace::Scaled(factor), Some(first), Some(at)) = (pace, first, req.timestamp) else {
+                return Ok(None);
+            };
+            let offset = at.duration_since(first).unwrap_or_default();
+            Duration::try_from_secs_f64(offset.as_secs_f64() / factor)
+                .ok()
+                .and_then(|delay| start.checked_add(delay))
+                .map(Some)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "request {} is recorded {offset:?} after the first, too far apart to replay at {factor:?}x",
+                        n + 1
+                    )
+                })
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct NdjsonRequest {
+    method: String,
+    url: String,
+    #[serde(default)]
+    headers: Option<NdjsonHeaders>,
+    service_id: Option<String>,
+    /// Seconds since the unix epoch.
+    timestamp: Option<f64>,
+}
+
+/// Headers either as `[["name", "value"], ...]`, which keeps order and
+/// repeats, or as `{"name": "value" | ["value", ...]}`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NdjsonHeaders {
+    Pairs(Vec<(String, String)>),
+    Map(serde_json::Map<String, serde_json::Value>),
+}
+
+impl NdjsonHeaders {
+    fn into_pairs(self) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let pairs = match self {
+            NdjsonHeaders::Pairs(pairs) => pairs
+                .into_iter()
+                .map(|(n, v)| (n, v.into_bytes()))
+                .collect(),
+            NdjsonHeaders::Map(map) => {
+                let mut pairs = Vec::new();
+                for (name, value) in map {
+                    match value {
+                        serde_json::Value::String(v) => pairs.push((name, v.into_bytes())),
+                        serde_json::Value::Array(values) => {
+                            for v in values {
+                                let v = v.as_str().ok_or_else(|| {
+                                    anyhow!("header {name} has a non-string value")
+                                })?;
+                                pairs.push((name.clone(), v.as_bytes().to_vec()));
+                            }
+                        }
+                        _ => bail!("header {name} must be a string or a list of strings"),
+                    }
+                }
+                pairs
+            }
+        };
+        Ok(pairs)
+    }
+}
+
+fn parse_ndjson(reader: impl BufRead, service_id: &str) -> Result<Vec<HostRequest>, Error> {
+    let mut requests = Vec::new();
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parse = || -> Result<HostRequest, Error> {
+            let r: NdjsonRequest = serde_json::from_str(&line)?;
+            let timestamp = match r.timestamp {
+                Some(secs) => {
+                    let since = Duration::try_from_secs_f64(secs)
+                        .map_err(|e| anyhow!("bad timestamp: {e}"))?;
+                    let at = UNIX_EPOCH
+                        .checked_add(since)
+                        .ok_or_else(|| anyhow!("bad timestamp: {secs} is out of range"))?;
+                    Some(at)
+                }
+                None => None,
+            };
+            Ok(HostRequest {
+                method: r.method,
+                url: r.url,
+                headers: r
+                    .headers
+                    .map(|h| h.into_pairs())
+                    .transpose()?
+                    .unwrap_or_default(),
+                service_id: r.service_id.unwrap_or_else(|| service_id.to_string()),
+                timestamp,
+            })
+        };
+        requests.push(parse().with_context(|| format!("line {}", n + 1))?);
+    }
+    Ok(requests)
+}
+
+#[derive(Deserialize)]
+struct Har {
+    log: HarLog,
+}
+
+#[derive(Deserialize)]
+struct HarLog {
+    entries: Vec<HarEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HarEntry {
+    started_date_time: String,
+    request: HarRequest,
+}
+
+#[derive(Deserialize)]
+struct HarRequest {
+    method: String,
+    url: String,
+    headers: Vec<HarHeader>,
+}
+
+#[derive(Deserialize)]
+struct HarHeader {
+    name: String,
+    value: String,
+}
+
+fn parse_har(reader: impl BufRead, service_id: &str) -> Result<Vec<HostRequest>, Error> {
+    let har: Har = serde_json::from_reader(reader)?;
+    let mut requests = Vec::with_capacity(har.log.entries.len());
+    for (n, entry) in har.log.entries.into_iter().enumerate() {
+        let started = chrono::DateTime::parse_from_rfc3339(&entry.started_date_time)
+            .with_context(|| format!("entry {n}: bad startedDateTime"))?;
+        let (authority, path) = split_url(&entry.request.url);
+
+        // HTTP/2 captures carry pseudo-headers instead of a Host header.
+        let mut headers: Vec<(String, Vec<u8>)> = entry
+            .request
+            .headers
+            .into_iter()
+            .filter(|h| !h.name.starts_with(':'))
+            .map(|h| (h.name, h.value.into_bytes()))
+            .collect();
+        if !headers.iter().any(|(n, _)| n.eq_ignore_ascii_case("host")) {
+            if let Some(authority) = authority {
+                headers.insert(0, ("Host".to_string(), authority.as_bytes().to_vec()));
+            }
+        }
+
+        requests.push(HostRequest {
+            method: entry.request.method,
+            url: path,
+            headers,
+            service_id: service_id.to_string(),
+            timestamp: Some(started.into()),
+        });
+    }
+    Ok(requests)
+}
+
+/// Splits an absolute URL into its authority and the path and query the
+/// request line would carry. URLs that are already paths come back as is.
+fn split_url(url: &str) -> (Option<&str>, String) {
+    let Some((_, rest)) = url.split_once("://") else {
+        return (None, url.to_string());
+    };
+    let rest = rest.split('#').next().unwrap_or_default();
+    match rest.find(['/', '?']) {
+        Some(i) if rest.as_bytes()[i] == b'/' => (Some(&rest[..i]), rest[i..].to_string()),
+        Some(i) => (Some(&rest[..i]), format!("/{}", &rest[i..])),
+        None => (Some(rest), "/".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> HostRequest {
+        HostRequest {
+            timestamp: Some(UNIX_EPOCH + Duration::from_secs(secs)),
+            ..HostRequest::get("/")
+        }
+    }
+
+    #[test]
+    fn input_spec_from_str() {
+        let spec: InputSpec = "har:capture.json".parse().unwrap();
+        assert_eq!(spec.format, InputFormat::Har);
+        assert_eq!(spec.path, Path::new("capture.json"));
+        let spec: InputSpec = "vsl:/tmp/http:log".parse().unwrap();
+        assert_eq!(spec.format, InputFormat::Vsl);
+        assert_eq!(spec.path, Path::new("/tmp/http:log"));
+
+        for (path, format) in [
+            ("a.http", InputFormat::Http),
+            ("dir/a.vsl", InputFormat::Vsl),
+            ("a.har", InputFormat::Har),
+            ("a.ndjson", InputFormat::Ndjson),
+            ("a.jsonl", InputFormat::Ndjson),
+        ] {
+            let spec: InputSpec = path.parse().unwrap();
+            assert_eq!(spec.format, format, "{path}");
+            assert_eq!(spec.path, Path::new(path));
+        }
+        for bad in ["a.json", "requests", "json:a.json"] {
+            assert!(bad.parse::<InputSpec>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn split_urls() {
+        assert_eq!(
+            split_url("https://cdn.example.com/v/seg1.m4s?x=1#frag"),
+            (Some("cdn.example.com"), "/v/seg1.m4s?x=1".to_string())
+        );
+        assert_eq!(
+            split_url("http://example.com:8080?q=/a"),
+            (Some("example.com:8080"), "/?q=/a".to_string())
+        );
+        assert_eq!(
+            split_url("http://example.com"),
+            (Some("example.com"), "/".to_string())
+        );
+        assert_eq!(
+            split_url("/already/a/path?x"),
+            (None, "/already/a/path?x".to_string())
+        );
+    }
+
+    #[test]
+    fn har() {
+        let har = r#"{"log": {"version": "1.2", "entries": [
+            {"startedDateTime": "2024-05-01T10:00:00.123+02:00",
+             "request": {"method": "GET", "url": "https://cdn.example.com/v/seg1.m4s?x=1",
+                         "httpVersion": "h2",
+                         "headers": [{"name": ":authority", "value": "cdn.example.com"},
+                                     {"name": "cmcd-request", "value": "bl=100"}]},
+             "response": {}},
+            {"startedDateTime": "2024-05-01T08:00:01Z",
+             "request": {"method": "POST", "url": "http://a.example/b",
+                         "headers": [{"name": "host", "value": "b.example"}]}}
+        ]}}"#;
+        let requests = parse_har(har.as_bytes(), "svc").unwrap();
+        assert_eq!(requests.len(), 2);
+
+        let first = &requests[0];
+        assert_eq!(first.method, "GET");
+        assert_eq!(first.url, "/v/seg1.m4s?x=1");
+        assert_eq!(first.service_id, "svc");
+        assert_eq!(
+            first.header_strs(),
+            [("Host", "cdn.example.com"), ("cmcd-request", "bl=100")]
+        );
+        assert_eq!(
+            first.timestamp,
+            Some(UNIX_EPOCH + Duration::from_millis(1_714_550_400_123))
+        );
+
+        // A Host header that's already there is left alone.
+        assert_eq!(requests[1].header_strs(), [("host", "b.example")]);
+        assert_eq!(
+            requests[1].timestamp,
+            Some(UNIX_EPOCH + Duration::from_secs(1_714_550_401))
+        );
+
+        let bad = r#"{"log": {"entries": [{"startedDateTime": "yesterday",
+            "request": {"method": "GET", "url": "/", "headers": []}}]}}"#;
+        assert!(parse_har(bad.as_bytes(), "svc").is_err());
+    }
+
+    #[test]
+    fn ndjson() {
+        let ndjson = r#"{"method":"GET","url":"/a","headers":{"Host":"x","CMCD-Request":["bl=1","br=2"]},"service_id":"nd1","timestamp":1700000000.5}
+
+{"method":"POST","url":"/b","headers":[["Host","y"],["X-Dup","1"],["X-Dup","2"]]}
+{"method":"GET","url":"/c"}
+"#;
+        let requests = parse_ndjson(ndjson.as_bytes(), "svc").unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].service_id, "nd1");
+        // Only the list form keeps headers in order.
+        assert_eq!(
+            requests[0].header_strs(),
+            [
+                ("CMCD-Request", "bl=1"),
+                ("CMCD-Request", "br=2"),
+                ("Host", "x")
+            ]
+        );
+        assert_eq!(
+            requests[0].timestamp,
+            Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_500))
+        );
+        assert_eq!(requests[1].method, "POST");
+        assert_eq!(requests[1].service_id, "svc");
+        assert_eq!(
+            requests[1].header_strs(),
+            [("Host", "y"), ("X-Dup", "1"), ("X-Dup", "2")]
+        );
+        assert_eq!(requests[1].timestamp, None);
+        assert!(requests[2].headers.is_empty());
+    }
+
+    #[test]
+    fn ndjson_errors_name_the_line() {
+        for (line, reason) in [
+            (r#"{"method":"GET"}"#, "missing field"),
+            (
+                r#"{"method":"GET","url":"/","headers":{"a":1}}"#,
+                "header a",
+            ),
+            (
+                r#"{"method":"GET","url":"/","timestamp":-1}"#,
+                "bad timestamp",
+            ),
+            (
+                r#"{"method":"GET","url":"/","timestamp":1e300}"#,
+                "bad timestamp",
+            ),
+        ] {
+            let ndjson = format!("{{\"method\":\"GET\",\"url\":\"/\"}}\n{line}\n");
+            let err = parse_ndjson(ndjson.as_bytes(), "svc").unwrap_err();
+            let err = format!("{err:#}");
+            assert!(err.starts_with("line 2: "), "{err}");
+            assert!(err.contains(reason), "{err}");
+        }
+    }
+
+    #[test]
+    fn pace_from_str() {
+        assert_eq!("asap".parse::<Pace>().unwrap(), Pace::Asap);
+        assert_eq!("realtime".parse::<Pace>().unwrap(), Pace::Scaled(1.0));
+        assert_eq!("10x".parse::<Pace>().unwrap(), Pace::Scaled(10.0));
+        assert_eq!("0.5".parse::<Pace>().unwrap(), Pace::Scaled(0.5));
+        for bad in ["", "fast", "x", "0x", "-2x", "infx", "NaNx"] {
+            assert!(bad.parse::<Pace>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn schedule_spaces_requests() {
+        let start = Instant::now();
+        let requests = [at(100), at(110), at(105)];
+        let scaled = schedule(&requests, Pace::Scaled(10.0), start).unwrap();
+        let delays: Vec<_> = scaled.iter().map(|at| at.unwrap() - start).collect();
+        assert_eq!(
+            delays,
+            [
+                Duration::ZERO,
+                Duration::from_secs(1),
+                Duration::from_millis(500)
+            ]
+        );
+        assert!(schedule(&requests, Pace::Asap, start)
+            .unwrap()
+            .iter()
+            .all(Option::is_none));
+    }
+
+    #[test]
+    fn schedule_rejects_unrepresentable_delays() {
+        let requests = [at(1), at(100_000)];
+        let err = schedule(&requests, Pace::Scaled(1e-300), Instant::now()).unwrap_err();
+        assert!(err.to_string().starts_with("request 2 "), "{err}");
+    }
+}
@@ -0,0 +1,38 @@
+//! Runs trace guests: components that pop requests from a host queue and
+//! log about them.
+//!
+//! [`HostBuilder`] loads a guest into an engine set up from a
+//! [`config::HostConfig`], and [`Host`] creates [`TraceInstance`]s of it to
+//! enter. The `host` binary is a command line front end to this.
+
+pub mod artifact;
+mod capi;
+pub mod config;
+mod coredump;
+pub mod epoch;
+pub mod event;
+mod fuel;
+mod host;
+pub mod input;
+mod limits;
+mod profile;
+pub mod queue;
+pub mod reload;
+pub mod request;
+pub mod serve;
+pub mod signing;
+pub mod sink;
+pub mod trap;
+pub mod vsl;
+pub mod worker;
+
+pub use crate::host::{compile, Host, HostBuilder, RunSummary, TraceInstance};
+
+wasmtime::component::bindgen!({
+    world: "trace",
+    async: true,
+    trappable_imports: true,
+    with: {
+        "fastly:varnish/types/req-resource": crate::request::HostRequest,
+    },
+});
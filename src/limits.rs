@@ -0,0 +1,261 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Error;
+use wasmtime::ResourceLimiter;
+
+use crate::config::LimitsConfig;
+use crate::host::human_bytes;
+
+/// A growth request the limiter turned down.
+#[derive(Debug, Clone, Copy)]
+pub enum LimitHit {
+    /// Linear memory would have grown to `desired` bytes in total.
+    Memory { desired: usize, limit: usize },
+    /// A table would have grown to `desired` elements.
+    Table { desired: u32, limit: u32 },
+}
+
+impl fmt::Display for LimitHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitHit::Memory { desired, limit } => write!(
+                f,
+                "guest hit its memory limit: wanted {} of linear memory, limit is {}",
+                human_bytes(*desired as u64),
+                human_bytes(*limit as u64)
+            ),
+            LimitHit::Table { desired, limit } => write!(
+                f,
+                "guest hit its table limit: wanted {desired} elements, limit is {limit}"
+            ),
+        }
+    }
+}
+
+/// Peak usage and limit hits across every store in a run. Clones share the
+/// same counters.
+#[derive(Clone, Default)]
+pub struct LimitReport {
+    inner: Arc<Mutex<LimitTotals>>,
+}
+
+#[derive(Default)]
+struct LimitTotals {
+    peak_memory: usize,
+    memory_hits: u64,
+    table_hits: u64,
+    /// Traps that followed a refused growth, and all others.
+    limit_traps: u64,
+    other_traps: u64,
+}
+
+impl LimitReport {
+    /// Counts a guest trap, blaming the limits if `hit` is set. Only called
+    /// for errors that are a [`wasmtime::Trap`].
+    pub fn trapped(&self, hit: Option<LimitHit>) {
+        let mut totals = self.inner.lock().unwrap();
+        match hit {
+            Some(_) => totals.limit_traps += 1,
+            None => totals.other_traps += 1,
+        }
+    }
+}
+
+impl fmt::Display for LimitReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let totals = self.inner.lock().unwrap();
+        write!(
+            f,
+            "peak linear memory {}, refused {} memory and {} table growths; {} traps after hitting a limit, {} other traps",
+            human_bytes(totals.peak_memory as u64),
+            totals.memory_hits,
+            totals.table_hits,
+            totals.limit_traps,
+            totals.other_traps,
+        )
+    }
+}
+
+/// Enforces the configured per-component limits on a store and tracks how
+/// much linear memory its guest uses.
+///
+/// A component can have several core memories; the memory limit applies to
+/// all of them together.
+pub struct GuestLimiter {
+    memory_limit: Option<usize>,
+    table_limit: Option<u32>,
+    /// Linear memory across all of the guest's memories.
+    memory: usize,
+    /// Size of the last growth allowed, taken back if it then fails.
+    last_growth: usize,
+    /// The most recent growth refused since [`GuestLimiter::take_hit`] was
+    /// last called.
+    hit: Option<LimitHit>,
+    report: LimitReport,
+}
+
+impl GuestLimiter {
+    pub fn new(config: &LimitsConfig, report: LimitReport) -> Self {
+        GuestLimiter {
+            memory_limit: config.memory_bytes,
+            table_limit: config.table_elements,
+            memory: 0,
+            last_growth: 0,
+            hit: None,
+            report,
+        }
+    }
+
+    /// Returns and clears the last refused growth.
+    pub fn take_hit(&mut self) -> Option<LimitHit> {
+        self.hit.take()
+    }
+}
+
+impl ResourceLimiter for GuestLimiter {
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        maximum: Option<usize>,
+    ) -> Result<bool, Error> {
+        if maximum.is_some_and(|max| desired > max) {
+            // Wasmtime refuses this itself, so there's nothing to account for.
+            self.last_growth = 0;
+            return Ok(true);
+        }
+        let total = self.memory - current + desired;
+        if let Some(limit) = self.memory_limit {
+            if total > limit {
+                self.hit = Some(LimitHit::Memory {
+                    desired: total,
+                    limit,
+                });
+                self.report.inner.lock().unwrap().memory_hits += 1;
+                return Ok(false);
+            }
+        }
+        self.memory = total;
+        self.last_growth = desired - current;
+        let mut totals = self.report.inner.lock().unwrap();
+        totals.peak_memory = totals.peak_memory.max(total);
+        Ok(true)
+    }
+
+    fn memory_grow_failed(&mut self, _error: Error) -> Result<(), Error> {
+        self.memory -= self.last_growth;
+        Ok(())
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        desired: u32,
+        _maximum: Option<u32>,
+    ) -> Result<bool, Error> {
+        if let Some(limit) = self.table_limit {
+            if desired > limit {
+                self.hit = Some(LimitHit::Table { desired, limit });
+                self.report.inner.lock().unwrap().table_hits += 1;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(memory_bytes: Option<usize>, table_elements: Option<u32>) -> GuestLimiter {
+        let config = LimitsConfig {
+            memory_bytes,
+            table_elements,
+        };
+        GuestLimiter::new(&config, LimitReport::default())
+    }
+
+    fn totals(limiter: &GuestLimiter) -> (usize, u64, u64) {
+        let totals = limiter.report.inner.lock().unwrap();
+        (totals.peak_memory, totals.memory_hits, totals.table_hits)
+    }
+
+    #[test]
+    fn memory_growth_past_the_limit_is_refused() {
+        let mut l = limiter(Some(3 * 65536), None);
+        assert!(l.memory_growing(0, 65536, None).unwrap());
+        assert!(l.memory_growing(65536, 2 * 65536, None).unwrap());
+        assert!(l.take_hit().is_none());
+
+        assert!(!l.memory_growing(2 * 65536, 4 * 65536, None).unwrap());
+        assert!(matches!(
+            l.take_hit(),
+            Some(LimitHit::Memory { desired, limit }) if desired == 4 * 65536 && limit == 3 * 65536
+        ));
+        assert!(l.take_hit().is_none());
+        assert_eq!(totals(&l), (2 * 65536, 1, 0));
+
+        // Growth up to the limit is still allowed.
+        assert!(l.memory_growing(2 * 65536, 3 * 65536, None).unwrap());
+        assert_eq!(totals(&l), (3 * 65536, 1, 0));
+    }
+
+    #[test]
+    fn memory_limit_covers_every_memory() {
+        let mut l = limiter(Some(2 * 65536), None);
+        assert!(l.memory_growing(0, 65536, None).unwrap());
+        // A second memory of the same size takes the total to the limit.
+        assert!(l.memory_growing(0, 65536, None).unwrap());
+        assert!(!l.memory_growing(0, 65536, None).unwrap());
+        assert!(matches!(l.take_hit(), Some(LimitHit::Memory { .. })));
+    }
+
+    #[test]
+    fn failed_growth_is_taken_back() {
+        let mut l = limiter(Some(2 * 65536), None);
+        assert!(l.memory_growing(0, 2 * 65536, None).unwrap());
+        l.memory_grow_failed(anyhow::anyhow!("out of memory"))
+            .unwrap();
+        assert!(l.memory_growing(0, 2 * 65536, None).unwrap());
+        assert!(l.take_hit().is_none());
+    }
+
+    #[test]
+    fn table_growth_past_the_limit_is_refused() {
+        let mut l = limiter(None, Some(100));
+        assert!(l.table_growing(0, 100, None).unwrap());
+        assert!(!l.table_growing(100, 101, None).unwrap());
+        assert!(matches!(
+            l.take_hit(),
+            Some(LimitHit::Table {
+                desired: 101,
+                limit: 100
+            })
+        ));
+        assert_eq!(totals(&l), (0, 0, 1));
+    }
+
+    #[test]
+    fn no_limits() {
+        let mut l = limiter(None, None);
+        assert!(l.memory_growing(0, 1 << 30, None).unwrap());
+        assert!(l.table_growing(0, u32::MAX, None).unwrap());
+        assert!(l.take_hit().is_none());
+    }
+
+    #[test]
+    fn report_counts_traps() {
+        let report = LimitReport::default();
+        report.trapped(Some(LimitHit::Table {
+            desired: 2,
+            limit: 1,
+        }));
+        report.trapped(None);
+        report.trapped(None);
+        assert!(report
+            .to_string()
+            .ends_with("1 traps after hitting a limit, 2 other traps"));
+    }
+}
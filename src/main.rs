@@ -1,168 +1,276 @@
+use std::net::SocketAddr;
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::path::PathBuf;
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Error};
 use clap::Parser;
-use wasmtime::component::{Component, Linker};
-use wasmtime::{
-    component::Resource, Config, Engine, InstanceAllocationStrategy, PoolingAllocationConfig,
-    Store, WasmBacktraceDetails,
-};
-use wasmtime_wasi::{ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
-
-use crate::fastly::varnish::types;
-
-wasmtime::component::bindgen!({
-    world: "trace",
-    async: true,
-});
-
-struct TraceCtx;
-
-struct Ctx {
-    table: ResourceTable,
-    wasi: WasiCtx,
-    varnish: TraceCtx,
-}
-
-#[async_trait::async_trait]
-impl crate::fastly::varnish::trace_log::Host for TraceCtx {
-    async fn log(&mut self, msg: String, endpoint: String, sid: String) {
-        println!("msg from guest is {msg}, to endpoint {endpoint} and sid {sid}");
-    }
-}
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+use host::config::{AllocationStrategy, HostConfig};
+use host::epoch::EpochMode;
+use host::event::{Events, HostEvent};
+use host::input::{self, InputSpec, Pace};
+use host::queue::{work_queue, WorkQueue};
+use host::request::HostRequest;
+use host::sink::{EndpointSink, SinkSpec};
+use host::trap::TrapFormat;
+use host::worker::WorkerOptions;
+use host::{reload, serve, signing, vsl, Host, HostBuilder};
 
-#[async_trait::async_trait]
-impl types::Host for TraceCtx {}
+#[derive(Parser, Debug)]
+struct Compile {
+    /// Path to the file to compile
+    input: PathBuf,
 
-// fn new(&mut self) -> wasmtime::component::Resource<docs::rpn::types::Engine> { /* ... */ }
-// fn push_operand(&mut self, self_: wasmtime::component::Resource<docs::rpn::types::Engine>) { /* ... */ }
+    /// Where to write the compiled file. Its compatibility metadata goes
+    /// next to it, in OUTPUT.meta.json.
+    output: PathBuf,
 
-#[async_trait::async_trait]
-impl crate::fastly::varnish::types::HostReqResource for TraceCtx {
-    async fn get_header_names(&mut self, _res: Resource<types::ReqResource>) -> Vec<Vec<u8>> {
-        vec![]
-    }
+    /// Host configuration, in TOML. The engine settings must match the ones
+    /// the compiled file is later run with.
+    #[arg(long)]
+    config: Option<PathBuf>,
 
-    async fn get(
-        &mut self,
-        _res: Resource<types::ReqResource>,
-        _header: String,
-    ) -> Option<Vec<Vec<u8>>> {
-        None
-    }
+    /// Compile with fuel metering, for running with `--fuel`
+    #[arg(long)]
+    fuel: bool,
 
-    async fn get_service_id(&mut self, _res: Resource<types::ReqResource>) -> String {
-        "sid".to_string()
-    }
+    /// Sign the compiled file with this secret key, from `host keygen`, so
+    /// hosts that trust its public key will load it
+    #[arg(long)]
+    sign_key: Option<PathBuf>,
+}
 
-    fn drop(&mut self, _res: Resource<types::ReqResource>) -> wasmtime::Result<()> {
-        Ok(())
-    }
+#[derive(Parser, Debug)]
+struct Keygen {
+    /// Where to write the secret key. The public key goes next to it, in
+    /// KEY.pub.
+    key: PathBuf,
 }
 
-#[async_trait::async_trait]
-impl crate::fastly::varnish::queue::Host for TraceCtx {
-    async fn try_pop(&mut self, _timeout_secs: u64) -> bool {
-        // this await is what is causing the issue
-        tokio::time::sleep(Duration::from_secs(1)).await;
-        false
-    }
+/// Options shared by every subcommand that runs a guest.
+#[derive(clap::Args, Debug)]
+struct GuestArgs {
+    /// Path to the file to run
+    file_name: PathBuf,
+
+    /// Fault option string
+    fault: Option<String>,
+
+    /// Host configuration, in TOML. Options given on the command line
+    /// override the file.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Route guest logs for an endpoint, as ENDPOINT=SINK. SINK is one of
+    /// `stdout`, `file:PATH` (NDJSON), `unix:PATH` or `http://HOST:PORT/PATH`.
+    /// Logs to endpoints without a sink are rejected. Defaults to
+    /// `cmcd=stdout` when neither this nor the config names any sinks.
+    #[arg(long = "sink")]
+    sinks: Vec<EndpointSink>,
+
+    /// Service id reported for queued requests
+    #[arg(long, default_value = "sid")]
+    service_id: String,
+
+    /// Number of requests the queue holds before producers have to wait
+    /// [default: 1024]
+    #[arg(long)]
+    queue_capacity: Option<NonZeroUsize>,
+
+    /// What to do when the guest reaches an epoch deadline [default: continue]
+    #[arg(long, value_enum)]
+    epoch_mode: Option<EpochMode>,
+
+    /// Milliseconds the guest may execute between pops before it is trapped
+    /// [default: 100]
+    #[arg(long)]
+    cpu_budget_ms: Option<NonZeroU64>,
+
+    /// How the engine allocates instances [default: pooling]
+    #[arg(long, value_enum)]
+    allocation: Option<AllocationStrategy>,
+
+    /// How to write reports of guest traps to stderr
+    #[arg(long, value_enum, default_value_t)]
+    trap_format: TrapFormat,
+
+    /// After SIGINT or SIGTERM, milliseconds guests get to finish the
+    /// requests already queued before they are killed [default: 10000]
+    #[arg(long)]
+    shutdown_grace_ms: Option<u64>,
+
+    /// Meter guest execution with fuel, giving it this much for each queued
+    /// request. A precompiled guest must be compiled with fuel enabled.
+    #[arg(long)]
+    fuel: Option<NonZeroU64>,
+
+    /// Only load a precompiled guest signed by the secret half of this
+    /// public key, or of any other given. Adds to `engine.trusted_keys`.
+    #[arg(long = "trusted-key")]
+    trusted_keys: Vec<PathBuf>,
 }
 
-impl WasiView for Ctx {
-    fn table(&mut self) -> &mut ResourceTable {
-        &mut self.table
+impl GuestArgs {
+    /// Loads the config file, if any, and applies the command line on top.
+    /// The file is checked before the options are applied, and clap checks
+    /// the options, so either error says where the bad value came from.
+    fn host_config(&self) -> Result<HostConfig, Error> {
+        let mut config = load_config(self.config.as_deref())?;
+        for s in &self.sinks {
+            config.sinks.insert(s.endpoint.clone(), s.sink.clone());
+        }
+        if config.sinks.is_empty() {
+            config.sinks.insert("cmcd".to_string(), SinkSpec::Stdout);
+        }
+        if let Some(capacity) = self.queue_capacity {
+            config.queue.capacity = capacity.get();
+        }
+        if let Some(mode) = self.epoch_mode {
+            config.epoch.mode = mode;
+        }
+        if let Some(budget) = self.cpu_budget_ms {
+            config.epoch.cpu_budget_ms = budget.get();
+        }
+        if let Some(allocation) = self.allocation {
+            config.engine.allocation = allocation;
+        }
+        if let Some(fuel) = self.fuel {
+            config.fuel.budget = Some(fuel.get());
+        }
+        if let Some(grace) = self.shutdown_grace_ms {
+            config.timeouts.shutdown_grace_ms = grace;
+        }
+        config
+            .engine
+            .trusted_keys
+            .extend(self.trusted_keys.iter().cloned());
+        config
+            .validate()
+            .context("invalid config after applying the command line options")?;
+        Ok(config)
     }
+}
 
-    fn ctx(&mut self) -> &mut WasiCtx {
-        &mut self.wasi
+fn load_config(path: Option<&std::path::Path>) -> Result<HostConfig, Error> {
+    match path {
+        Some(path) => HostConfig::load(path),
+        None => Ok(HostConfig::default()),
     }
 }
 
-pub fn my_add_to_linker<T: WasiView>(l: &mut wasmtime::component::Linker<T>) -> anyhow::Result<()> {
-    let _ = l;
-
-    wasmtime_wasi::add_to_linker_async(l)?;
-    Ok(())
+/// Options for re-entering the guest in a long-running worker.
+#[derive(clap::Args, Debug)]
+struct WorkerArgs {
+    /// Number of workers entering the guest concurrently, each with its own
+    /// store. More than one implies `--worker` for `run`.
+    #[arg(long, default_value = "1")]
+    workers: NonZeroUsize,
+
+    /// Reuse the store and instance between calls to `enter` instead of
+    /// instantiating afresh each time
+    #[arg(long)]
+    reuse_store: bool,
+
+    /// Milliseconds to wait before restarting a trapped guest. Doubles with
+    /// each consecutive trap.
+    #[arg(long, default_value_t = 100)]
+    restart_backoff_ms: u64,
+
+    /// Upper bound on the restart backoff, in milliseconds
+    #[arg(long, default_value_t = 10_000)]
+    max_restart_backoff_ms: u64,
+
+    /// Reload the guest when its file changes, as well as on SIGHUP
+    #[arg(long)]
+    watch: bool,
+
+    /// Milliseconds between checks of the guest file for `--watch`
+    #[arg(long, default_value_t = 1000)]
+    watch_interval_ms: u64,
 }
 
-fn create_engine() -> Result<(Engine, Linker<Ctx>), Error> {
-    let pooling_allocation_config = make_pooling_config();
-
-    let mut config = Config::default();
-    config.wasm_component_model(true);
-    config.async_support(true);
-    config.wasm_backtrace_details(WasmBacktraceDetails::Enable);
-    config.epoch_interruption(true);
-
-    config.allocation_strategy(InstanceAllocationStrategy::Pooling(
-        pooling_allocation_config,
-    ));
-
-    let engine = Engine::new(&config)?;
-
-    let mut linker: Linker<Ctx> = Linker::new(&engine);
-    Trace::add_to_linker(&mut linker, |ctx| &mut ctx.varnish)?;
-    my_add_to_linker(&mut linker)?;
-
-    Ok((engine, linker))
+impl WorkerArgs {
+    fn options(&self) -> WorkerOptions {
+        WorkerOptions {
+            workers: self.workers.get(),
+            reuse_store: self.reuse_store,
+            min_backoff: Duration::from_millis(self.restart_backoff_ms),
+            max_backoff: Duration::from_millis(self.max_restart_backoff_ms),
+        }
+    }
 }
 
-fn make_pooling_config() -> PoolingAllocationConfig {
-    const MB: usize = 1 << 20;
-    let mut pooling_allocation_config = PoolingAllocationConfig::default();
-
-    // This number matches Compute production
-    pooling_allocation_config.max_core_instance_size(MB);
-
-    // Core wasm programs have 1 memory
-    // This is where things get expensive. Each of these reserves virtual memory,
-    pooling_allocation_config.total_memories(100);
-    pooling_allocation_config.max_memories_per_module(1);
-
-    // allow for up to 2MiB of linear memory. Wasm pages are 64k
-    pooling_allocation_config.memory_pages(2 * (MB as u64) / (64 * 1024));
-
-    // Core wasm programs have 1 table
-    pooling_allocation_config.max_tables_per_module(1);
+#[derive(Parser, Debug)]
+struct Run {
+    #[command(flatten)]
+    guest: GuestArgs,
+
+    /// Files each holding a raw HTTP/1.1 request to queue for the guest
+    #[arg(long = "request")]
+    requests: Vec<PathBuf>,
+
+    /// Recorded requests to queue for the guest, as FORMAT:PATH where FORMAT
+    /// is one of http, vsl, har or ndjson. The format can be left off when
+    /// the file extension gives it away.
+    #[arg(long = "input")]
+    inputs: Vec<InputSpec>,
+
+    /// How quickly to queue requests: `asap`, `realtime` to keep their
+    /// recorded spacing, or a speed-up such as `10x`
+    #[arg(long, default_value = "asap")]
+    pace: Pace,
+
+    /// Write a wasm core dump and a JSON trap report into this directory
+    /// whenever the guest traps
+    #[arg(long)]
+    coredump_dir: Option<PathBuf>,
+
+    /// Sample the guest's stack on every epoch tick and write a profile for
+    /// https://profiler.firefox.com/ to this file. Needs a single worker
+    #[arg(long)]
+    profile: Option<PathBuf>,
+
+    /// Keep re-entering the guest until the queue closes or the process is
+    /// interrupted, restarting it after traps
+    #[arg(long)]
+    worker: bool,
+
+    #[command(flatten)]
+    worker_args: WorkerArgs,
+}
 
-    // Some applications create a large number of functions, in particular
-    // when compiled in debug mode or applications written in swift. Every
-    // function can end up in the table
-    pooling_allocation_config.table_elements(98765);
+#[derive(Parser, Debug)]
+struct Serve {
+    #[command(flatten)]
+    guest: GuestArgs,
 
-    // Maximum number of slots in the pooling allocator to keep "warm", or those
-    // to keep around to possibly satisfy an affine allocation request or an
-    // instantiation of a module previously instantiated within the pool.
-    pooling_allocation_config.max_unused_warm_slots(10);
+    /// Address to accept HTTP requests on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
 
-    // Use a large pool, but one smaller than the default of 1000 to avoid runnign out of virtual
-    // memory space if multiple engines are spun up in a single process. We'll likely want to move
-    // to the on-demand allocator eventually for most purposes; see
-    // https://github.com/fastly/Viceroy/issues/255
-    pooling_allocation_config.total_core_instances(100);
-    pooling_allocation_config
+    #[command(flatten)]
+    worker_args: WorkerArgs,
 }
 
 #[derive(Parser, Debug)]
-struct Compile {
-    /// Path to the file to compile
-    input: PathBuf,
+struct Replay {
+    #[command(flatten)]
+    guest: GuestArgs,
 
-    /// Where to write the compiled file
-    output: PathBuf,
-}
+    /// `varnishlog` text output to take requests from
+    #[arg(long)]
+    vsl: PathBuf,
 
-#[derive(Parser, Debug)]
-struct Run {
-    /// Path to the file to run
-    file_name: PathBuf,
+    /// How quickly to queue requests: `asap`, `realtime` to keep their
+    /// recorded spacing, or a speed-up such as `10x`
+    #[arg(long, default_value = "asap")]
+    pace: Pace,
 
-    /// Fault option string
-    fault: Option<String>,
+    #[command(flatten)]
+    worker_args: WorkerArgs,
 }
 
 #[derive(Parser, Debug)]
@@ -171,80 +279,184 @@ enum Cli {
     Compile(Compile),
     /// Run the specified machine code.
     Run(Run),
+    /// Run the specified machine code as a worker fed by HTTP requests.
+    Serve(Serve),
+    /// Run the specified machine code against requests recorded by varnishlog.
+    Replay(Replay),
+    /// Generate a key pair for signing compiled machine code.
+    Keygen(Keygen),
 }
 
 async fn do_compile(c: Compile) -> Result<(), Error> {
-    let (engine, linker) = create_engine()?;
-    let component = Component::from_file(&engine, c.input)?;
-    let _varnish_pre = linker
-        .instantiate_pre(&component)
-        .context("conforms to Varnish world")?;
-    let serialized = component.serialize()?;
-    std::fs::write(c.output, serialized)?;
+    let mut config = load_config(c.config.as_deref())?;
+    if c.fuel && config.fuel.budget.is_none() {
+        // Only whether fuel is on matters for compilation, not the budget.
+        config.fuel.budget = Some(u64::MAX);
+    }
+    let sign_key = c
+        .sign_key
+        .as_deref()
+        .map(signing::read_signing_key)
+        .transpose()?;
+    host::compile(&config, &c.input, &c.output, sign_key.as_ref())?;
+    Ok(())
+}
+
+fn do_keygen(k: Keygen) -> Result<(), Error> {
+    let public = signing::generate_key(&k.key)?;
+    eprintln!(
+        "wrote secret key {} and public key {}",
+        k.key.display(),
+        public.display()
+    );
     Ok(())
 }
 
 async fn do_run(r: Run) -> Result<(), Error> {
-    let (engine, linker) = create_engine()?;
+    let mut config = r.guest.host_config()?;
+    if let Some(dir) = &r.coredump_dir {
+        config.engine.coredump_dir = Some(dir.clone());
+    }
+    if let Some(path) = &r.profile {
+        config.engine.profile = Some(path.clone());
+    }
+    let mut requests = Vec::with_capacity(r.requests.len());
+    for path in &r.requests {
+        let raw = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let req = HostRequest::parse(&raw, r.guest.service_id.as_str())
+            .with_context(|| format!("parsing request in {}", path.display()))?;
+        requests.push(req);
+    }
+    for input in &r.inputs {
+        requests.extend(input.load(&r.guest.service_id)?);
+    }
 
-    let component = if r.file_name.extension().map(|e| e.to_str().unwrap()) == Some("cwasm") {
-        unsafe { Component::deserialize_file(&engine, &r.file_name) }?
-    } else {
-        Component::from_file(&engine, &r.file_name)?
-    };
+    let queue = feed_queue(requests, config.queue.capacity, r.pace)?;
+    let worker = (r.worker || r.worker_args.workers.get() > 1).then_some(&r.worker_args);
+    run_guest(&r.guest, config, queue, worker).await
+}
 
-    // TODO: load out of a pre-compiled shared object instead of compiling on
-    // demand
-    let varnish_pre = linker.instantiate_pre(&component)?;
-
-    // all this loops
-    let wasi = WasiCtxBuilder::new()
-        .env("INJECT_FAULT", r.fault.unwrap_or_default())
-        .build();
-    let ctx = Ctx {
-        table: ResourceTable::new(),
-        wasi,
-        varnish: TraceCtx,
-    };
-    let mut store = Store::new(&engine, ctx);
-    store.set_epoch_deadline(10);
-    store.epoch_deadline_trap();
-
-    let (trace, _instance) = Trace::instantiate_pre(&mut store, &varnish_pre).await?;
-
-    let ticker_stop = Arc::new(AtomicBool::new(false));
-
-    let engine_for_ticker = engine.clone();
-    let stop_for_ticker = ticker_stop.clone();
-    let ticker_handle = std::thread::Builder::new()
-        .name("wasm-epoch-ticker".into())
-        .spawn(|| {
-            let engine = engine_for_ticker;
-            let stop = stop_for_ticker;
-            while !stop.load(std::sync::atomic::Ordering::Relaxed) {
-                std::thread::sleep(Duration::from_millis(10));
-                engine.increment_epoch();
+async fn do_serve(s: Serve) -> Result<(), Error> {
+    let config = s.guest.host_config()?;
+    let listener = TcpListener::bind(s.listen)
+        .await
+        .with_context(|| format!("binding {}", s.listen))?;
+    eprintln!("accepting requests on http://{}", listener.local_addr()?);
+
+    let (tx, queue) = work_queue(config.queue.capacity);
+    tokio::spawn(serve::accept_loop(
+        listener,
+        tx,
+        s.guest.service_id.clone(),
+        print_events(s.guest.trap_format),
+    ));
+
+    run_guest(&s.guest, config, queue, Some(&s.worker_args)).await
+}
+
+async fn do_replay(r: Replay) -> Result<(), Error> {
+    let config = r.guest.host_config()?;
+    let file =
+        std::fs::File::open(&r.vsl).with_context(|| format!("opening {}", r.vsl.display()))?;
+    let requests = vsl::parse(std::io::BufReader::new(file), &r.guest.service_id)
+        .with_context(|| format!("parsing {}", r.vsl.display()))?;
+    eprintln!("replaying {} requests", requests.len());
+
+    let queue = feed_queue(requests, config.queue.capacity, r.pace)?;
+    run_guest(&r.guest, config, queue, Some(&r.worker_args)).await
+}
+
+/// Queues `requests` in the background, closing the queue once they have
+/// all been sent. The closed queue is how the guest learns there's no more
+/// work.
+fn feed_queue(requests: Vec<HostRequest>, capacity: usize, pace: Pace) -> Result<WorkQueue, Error> {
+    let (tx, queue) = work_queue(capacity);
+    tokio::spawn(input::feed(requests, tx, pace)?);
+    Ok(queue)
+}
+
+/// Loads the guest and enters it, either once or as a worker, feeding it
+/// from `queue`.
+async fn run_guest(
+    g: &GuestArgs,
+    config: HostConfig,
+    queue: WorkQueue,
+    worker: Option<&WorkerArgs>,
+) -> Result<(), Error> {
+    if let Some(args) = worker {
+        args.options().check(&config)?;
+    }
+    let mut builder = HostBuilder::new()
+        .config(config)
+        .queue(queue)
+        .events(print_events(g.trap_format));
+    if let Some(fault) = &g.fault {
+        builder = builder.fault(fault);
+    }
+    let host = builder.build(&g.file_name).await?;
+    eprintln!("{}", host.reservation());
+    if let Some(why) = host.recompiled() {
+        eprintln!("{why}");
+    }
+
+    // Signals are watched on a task of their own, since a guest that never
+    // yields keeps this one busy.
+    let mut tasks = vec![tokio::spawn(handle_signals(host.clone()))];
+    let result = match worker {
+        Some(args) => {
+            tasks.push(tokio::spawn(reload_on_sighup(host.clone())));
+            if args.watch {
+                let interval = Duration::from_millis(args.watch_interval_ms);
+                tasks.push(tokio::spawn(reload::watch(host.clone(), interval)));
             }
-        })
-        .expect("can spawn thread");
-
-    // loop over this to re-enter the module
-    let f = async {
-        trace
-            .fastly_varnish_trace_hooks()
-            .call_enter(&mut store)
-            .await
+            host.run_workers(&args.options()).await
+        }
+        None => host.run_once().await,
     };
+    for task in tasks {
+        task.abort();
+    }
 
-    let () = tokio::time::timeout(Duration::from_secs(1), f)
-        .await
-        .context("guest timed out")?
-        .context("guest trapped")?;
+    eprintln!("{}", host.finish().await?);
+    result
+}
 
-    ticker_stop.store(true, std::sync::atomic::Ordering::Relaxed);
-    let () = ticker_handle.join().unwrap();
+/// Prints whatever the host reports to stderr, with trap reports in
+/// `format`.
+fn print_events(format: TrapFormat) -> Events {
+    Events::new(move |event| match event {
+        HostEvent::Trapped(report) => eprintln!("{}", report.render(format)),
+        event => eprintln!("{event}"),
+    })
+}
 
-    Ok(())
+async fn next_signal(int: &mut Signal, term: &mut Signal) -> &'static str {
+    tokio::select! {
+        _ = int.recv() => "SIGINT",
+        _ = term.recv() => "SIGTERM",
+    }
+}
+
+async fn reload_on_sighup(host: Host) {
+    let mut hup = signal(SignalKind::hangup()).expect("can listen for SIGHUP");
+    while hup.recv().await.is_some() {
+        reload::reload(&host).await;
+    }
+}
+
+/// Shuts the host down gracefully on the first SIGINT or SIGTERM, and kills
+/// its guests on the second.
+async fn handle_signals(host: Host) {
+    let mut int = signal(SignalKind::interrupt()).expect("can listen for SIGINT");
+    let mut term = signal(SignalKind::terminate()).expect("can listen for SIGTERM");
+    let signal = next_signal(&mut int, &mut term).await;
+    let grace = host.config().timeouts.shutdown_grace();
+    eprintln!("received {signal}, draining the queue for up to {grace:?}");
+    host.shutdown(grace);
+
+    let signal = next_signal(&mut int, &mut term).await;
+    eprintln!("received {signal} again, killing guests");
+    host.kill();
 }
 
 #[tokio::main]
@@ -254,6 +466,9 @@ async fn main() -> Result<(), Error> {
     match args {
         Cli::Compile(c) => do_compile(c).await?,
         Cli::Run(r) => do_run(r).await?,
+        Cli::Serve(s) => do_serve(s).await?,
+        Cli::Replay(r) => do_replay(r).await?,
+        Cli::Keygen(k) => do_keygen(k)?,
     }
     Ok(())
 }
@@ -0,0 +1,90 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Error};
+use wasmtime::{AsContext, GuestProfiler, Module, WasmBacktrace};
+
+use crate::coredump::same_module;
+
+/// Samples the guest's stack on every epoch tick and writes the result in
+/// the Firefox profiler's format.
+///
+/// A `GuestProfiler` follows one thread of guest execution, so only one
+/// store may be sampled at a time; `Host::run_workers` refuses to profile
+/// more than one worker. Stores created to restart a trapped guest take over
+/// from the one before them.
+///
+/// `GuestProfiler` has to be told which core modules to symbolicate up
+/// front, and a component doesn't reveal the ones inside it. So the profiler
+/// is only created at the first sample, from the modules on the stack at
+/// that point. Frames in modules that only show up later, such as the WASI
+/// adapter, are left out of the profile.
+#[derive(Clone)]
+pub struct SharedProfiler {
+    inner: Arc<Mutex<Profiler>>,
+}
+
+struct Profiler {
+    name: String,
+    interval: Duration,
+    profiler: Option<GuestProfiler>,
+}
+
+impl SharedProfiler {
+    pub fn new(name: &str, interval: Duration) -> Self {
+        SharedProfiler {
+            inner: Arc::new(Mutex::new(Profiler {
+                name: name.to_string(),
+                interval,
+                profiler: None,
+            })),
+        }
+    }
+
+    /// Records the guest's current stack. Called from the epoch deadline
+    /// callback, so each sample stands for one tick of guest execution.
+    pub fn sample(&self, store: impl AsContext) {
+        let mut p = self.inner.lock().unwrap();
+        let p = &mut *p;
+        let interval = p.interval;
+        let profiler = match &mut p.profiler {
+            Some(profiler) => profiler,
+            None => {
+                let modules = modules_on_stack(&store, &p.name);
+                if modules.is_empty() {
+                    return;
+                }
+                p.profiler
+                    .insert(GuestProfiler::new(&p.name, interval, modules))
+            }
+        };
+        profiler.sample(&store, interval);
+    }
+
+    /// Writes the profile out. Fails if the guest never ran long enough to be
+    /// sampled.
+    pub fn finish(&self, path: &Path) -> Result<(), Error> {
+        let Some(profiler) = self.inner.lock().unwrap().profiler.take() else {
+            bail!("the guest was never sampled, so there is no profile to write");
+        };
+        let file =
+            std::fs::File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        profiler
+            .finish(std::io::BufWriter::new(file))
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+fn modules_on_stack(store: impl AsContext, fallback_name: &str) -> Vec<(String, Module)> {
+    let backtrace = WasmBacktrace::force_capture(store);
+    let mut modules: Vec<(String, Module)> = Vec::new();
+    for frame in backtrace.frames() {
+        let module = frame.module();
+        if !modules.iter().any(|(_, seen)| same_module(seen, module)) {
+            let name = module.name().unwrap_or(fallback_name).to_string();
+            modules.push((name, module.clone()));
+        }
+    }
+    modules
+}
@@ -0,0 +1,275 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, watch, Mutex};
+
+use crate::request::HostRequest;
+
+/// A request along with when it was offered to the queue.
+struct Queued {
+    req: HostRequest,
+    at: Instant,
+}
+
+/// The producing end of a [`WorkQueue`]. The queue is closed once every
+/// sender has been dropped.
+#[derive(Clone)]
+pub struct QueueSender {
+    tx: mpsc::Sender<Queued>,
+}
+
+impl QueueSender {
+    /// Adds a request, waiting for room if the queue is full. Hands the
+    /// request back if the queue has gone away.
+    pub async fn send(&self, req: HostRequest) -> Result<(), HostRequest> {
+        let queued = Queued {
+            req,
+            at: Instant::now(),
+        };
+        self.tx.send(queued).await.map_err(|e| e.0.req)
+    }
+
+    /// Resolves once the queue stops taking requests.
+    pub async fn closed(&self) {
+        self.tx.closed().await
+    }
+
+    /// Adds a request if there's room, without waiting.
+    pub fn try_send(&self, req: HostRequest) -> Result<(), TrySendError> {
+        let queued = Queued {
+            req,
+            at: Instant::now(),
+        };
+        self.tx.try_send(queued).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(q) => TrySendError::Full(q.req),
+            mpsc::error::TrySendError::Closed(q) => TrySendError::Closed(q.req),
+        })
+    }
+}
+
+/// Why [`QueueSender::try_send`] handed a request back.
+#[derive(Debug)]
+pub enum TrySendError {
+    Full(HostRequest),
+    Closed(HostRequest),
+}
+
+/// Requests waiting to be handed to the guest through `queue.try-pop`.
+///
+/// Clones share the same underlying queue, so several stores can pop from
+/// it.
+#[derive(Clone)]
+pub struct WorkQueue {
+    rx: Arc<Mutex<mpsc::Receiver<Queued>>>,
+    /// Set once a pop has seen the queue closed and drained.
+    finished: Arc<AtomicBool>,
+    /// Set by [`WorkQueue::close`].
+    closing: Arc<watch::Sender<bool>>,
+    stats: Arc<std::sync::Mutex<QueueStats>>,
+}
+
+/// Creates a queue holding at most `capacity` requests. Senders wait for
+/// room once it is full.
+pub fn work_queue(capacity: usize) -> (QueueSender, WorkQueue) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let queue = WorkQueue {
+        rx: Arc::new(Mutex::new(rx)),
+        finished: Arc::new(AtomicBool::new(false)),
+        closing: Arc::new(watch::Sender::new(false)),
+        stats: Default::default(),
+    };
+    (QueueSender { tx }, queue)
+}
+
+impl WorkQueue {
+    /// Waits up to `timeout` for the next request. Returns `None` on timeout
+    /// or as soon as the queue is closed and drained.
+    pub async fn pop(&self, timeout: Duration) -> Option<HostRequest> {
+        let recv = async {
+            let mut rx = self.rx.lock().await;
+            let mut closing = self.closing.subscribe();
+            if !*closing.borrow() {
+                tokio::select! {
+                    queued = rx.recv() => return queued,
+                    _ = closing.wait_for(|closing| *closing) => {}
+                }
+            }
+            rx.close();
+            rx.try_recv().ok()
+        };
+        match tokio::time::timeout(timeout, recv).await {
+            Ok(Some(queued)) => {
+                self.stats.lock().unwrap().record(queued.at);
+                Some(queued.req)
+            }
+            Ok(None) => {
+                self.finished.store(true, Ordering::Relaxed);
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Stops taking requests, even while senders remain. Those already queued
+    /// are still handed out, but once they're gone pops return `None` without
+    /// waiting.
+    pub fn close(&self) {
+        self.closing.send_replace(true);
+        // Whoever holds the receiver closes it on seeing the flag.
+        if let Ok(mut rx) = self.rx.try_lock() {
+            rx.close();
+        }
+    }
+
+    /// Whether the queue has been closed and everything in it popped.
+    pub fn is_finished(&self) -> bool {
+        if self.finished.load(Ordering::Relaxed) {
+            return true;
+        }
+        // Without this, a guest that never pops would never find out.
+        let Ok(rx) = self.rx.try_lock() else {
+            return false;
+        };
+        let finished = (rx.is_closed() || self.is_closing()) && rx.is_empty();
+        if finished {
+            self.finished.store(true, Ordering::Relaxed);
+        }
+        finished
+    }
+
+    /// Whether [`WorkQueue::close`] has been called.
+    pub fn is_closing(&self) -> bool {
+        *self.closing.borrow()
+    }
+
+    /// Resolves once [`WorkQueue::close`] has been called.
+    pub async fn closing(&self) {
+        let _ = self.closing.subscribe().wait_for(|closing| *closing).await;
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+/// How quickly requests left the queue and how long they sat in it.
+#[derive(Debug, Clone, Default)]
+pub struct QueueStats {
+    pub popped: u64,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+    pub first_pop: Option<Instant>,
+    pub last_pop: Option<Instant>,
+}
+
+impl QueueStats {
+    fn record(&mut self, queued_at: Instant) {
+        let now = Instant::now();
+        let wait = now - queued_at;
+        self.popped += 1;
+        self.total_wait += wait;
+        self.max_wait = self.max_wait.max(wait);
+        self.first_pop.get_or_insert(now);
+        self.last_pop = Some(now);
+    }
+
+    /// Requests popped per second between the first and last pop.
+    pub fn throughput(&self) -> Option<f64> {
+        let elapsed = self.last_pop? - self.first_pop?;
+        (self.popped > 1 && !elapsed.is_zero())
+            .then(|| (self.popped - 1) as f64 / elapsed.as_secs_f64())
+    }
+
+    pub fn mean_wait(&self) -> Duration {
+        if self.popped == 0 {
+            return Duration::ZERO;
+        }
+        self.total_wait.div_f64(self.popped as f64)
+    }
+}
+
+impl fmt::Display for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "popped {} requests", self.popped)?;
+        if let Some(rate) = self.throughput() {
+            write!(f, " at {rate:.1} req/s")?;
+        }
+        write!(
+            f,
+            ", queue wait mean {:?} max {:?}",
+            self.mean_wait(),
+            self.max_wait
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn pop_times_out_while_open() {
+        let (tx, queue) = work_queue(4);
+        let start = Instant::now();
+        assert!(queue.pop(WAIT).await.is_none());
+        assert!(start.elapsed() >= WAIT);
+        assert!(!queue.is_finished());
+
+        tx.send(HostRequest::get("/a")).await.unwrap();
+        assert_eq!(queue.pop(WAIT).await.unwrap().url, "/a");
+        assert_eq!(queue.stats().popped, 1);
+    }
+
+    #[tokio::test]
+    async fn close_hands_out_what_is_left() {
+        let (tx, queue) = work_queue(4);
+        tx.send(HostRequest::get("/a")).await.unwrap();
+        tx.send(HostRequest::get("/b")).await.unwrap();
+        queue.close();
+        assert!(queue.is_closing());
+        assert!(!queue.is_finished());
+        assert!(matches!(
+            tx.try_send(HostRequest::get("/c")),
+            Err(TrySendError::Closed(_))
+        ));
+
+        assert_eq!(queue.pop(WAIT).await.unwrap().url, "/a");
+        assert_eq!(queue.pop(WAIT).await.unwrap().url, "/b");
+        let start = Instant::now();
+        assert!(queue.pop(Duration::from_secs(10)).await.is_none());
+        assert!(start.elapsed() < WAIT);
+        assert!(queue.is_finished());
+    }
+
+    #[tokio::test]
+    async fn close_wakes_a_waiting_pop() {
+        let (_tx, queue) = work_queue(4);
+        let popping = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop(Duration::from_secs(10)).await }
+        });
+        tokio::time::sleep(WAIT).await;
+        queue.close();
+        let popped = tokio::time::timeout(Duration::from_secs(1), popping)
+            .await
+            .expect("pop returns once the queue is closed")
+            .unwrap();
+        assert!(popped.is_none());
+        assert!(queue.is_finished());
+    }
+
+    #[tokio::test]
+    async fn dropping_senders_finishes_the_queue() {
+        let (tx, queue) = work_queue(4);
+        tx.send(HostRequest::get("/a")).await.unwrap();
+        drop(tx);
+        assert!(!queue.is_finished());
+        assert_eq!(queue.pop(WAIT).await.unwrap().url, "/a");
+        assert!(queue.pop(WAIT).await.is_none());
+        assert!(queue.is_finished());
+    }
+}
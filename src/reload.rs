@@ -0,0 +1,63 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::artifact::meta_path;
+use crate::event::HostEvent;
+use crate::Host;
+
+/// Reloads the guest, reporting which version is now running as a
+/// [`HostEvent::Reloaded`] or [`HostEvent::ReloadFailed`].
+pub async fn reload(host: &Host) {
+    let event = match host.reload().await {
+        Ok(version) => HostEvent::Reloaded {
+            path: host.guest_path().to_path_buf(),
+            version,
+            recompiled: host.recompiled(),
+        },
+        Err(error) => HostEvent::ReloadFailed {
+            version: host.version(),
+            error,
+        },
+    };
+    host.events().emit(event);
+}
+
+/// Reloads the guest whenever its file changes, checking every `interval`.
+/// For a `.cwasm`, changes to its metadata count too.
+///
+/// A change is only acted on once the file has looked the same for a whole
+/// interval, so a new version that's still being written isn't picked up
+/// half way through. A file that has gone missing is waited out.
+pub async fn watch(host: Host, interval: Duration) {
+    let mut loaded = stamp(host.guest_path());
+    let mut changed = None;
+    loop {
+        tokio::time::sleep(interval).await;
+        let now = stamp(host.guest_path());
+        if now.is_none() || now == loaded {
+            changed = None;
+        } else if changed != now {
+            changed = now;
+        } else {
+            loaded = now;
+            changed = None;
+            reload(&host).await;
+        }
+    }
+}
+
+/// When the guest file, and for a `.cwasm` its metadata, were last modified
+/// and how big they are.
+fn stamp(path: &Path) -> Option<Vec<(SystemTime, u64)>> {
+    let mut files = vec![path.to_path_buf()];
+    if path.extension().is_some_and(|e| e == "cwasm") {
+        files.push(meta_path(path));
+    }
+    files
+        .iter()
+        .map(|file| {
+            let meta = std::fs::metadata(file).ok()?;
+            Some((meta.modified().ok()?, meta.len()))
+        })
+        .collect()
+}
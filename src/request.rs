@@ -0,0 +1,178 @@
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Error};
+
+/// Maximum number of headers accepted when parsing a request.
+const MAX_HEADERS: usize = 128;
+
+/// A client request as seen by the guest through `types.req-resource`.
+#[derive(Debug, Clone)]
+pub struct HostRequest {
+    pub method: String,
+    pub url: String,
+    /// Headers in the order they were received. Names may repeat.
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub service_id: String,
+    /// When the request was originally received, for recorded traffic.
+    pub timestamp: Option<SystemTime>,
+}
+
+impl HostRequest {
+    /// Parses the head of an HTTP/1.1 request. Anything after the blank line
+    /// ending the headers is ignored.
+    pub fn parse(raw: &[u8], service_id: impl Into<String>) -> Result<Self, Error> {
+        match Self::parse_head(raw, service_id)? {
+            Some((req, _)) => Ok(req),
+            None => bail!("incomplete HTTP request head"),
+        }
+    }
+
+    /// Like [`HostRequest::parse`], but returns `None` if `raw` doesn't hold
+    /// a complete head yet. On success also returns the length of the head.
+    pub fn parse_head(
+        raw: &[u8],
+        service_id: impl Into<String>,
+    ) -> Result<Option<(Self, usize)>, Error> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        let len = match req.parse(raw)? {
+            httparse::Status::Complete(len) => len,
+            httparse::Status::Partial => return Ok(None),
+        };
+        let req = HostRequest {
+            method: req.method.ok_or_else(|| anyhow!("missing method"))?.into(),
+            url: req.path.ok_or_else(|| anyhow!("missing url"))?.into(),
+            headers: req
+                .headers
+                .iter()
+                .map(|h| (h.name.to_string(), h.value.to_vec()))
+                .collect(),
+            service_id: service_id.into(),
+            timestamp: None,
+        };
+        Ok(Some((req, len)))
+    }
+
+    /// Header names without duplicates, in order of first appearance.
+    pub fn header_names(&self) -> Vec<Vec<u8>> {
+        let mut names: Vec<Vec<u8>> = Vec::new();
+        for (name, _) in &self.headers {
+            if !names
+                .iter()
+                .any(|n| n.eq_ignore_ascii_case(name.as_bytes()))
+            {
+                names.push(name.as_bytes().to_vec());
+            }
+        }
+        names
+    }
+
+    /// All values of a header, compared case-insensitively, or `None` if the
+    /// request doesn't carry it.
+    pub fn header(&self, name: &str) -> Option<Vec<Vec<u8>>> {
+        let values: Vec<Vec<u8>> = self
+            .headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.clone())
+            .collect();
+        (!values.is_empty()).then_some(values)
+    }
+}
+
+#[cfg(test)]
+impl HostRequest {
+    /// A GET for `url` without headers, for tests to build on.
+    pub(crate) fn get(url: &str) -> Self {
+        HostRequest {
+            method: "GET".to_string(),
+            url: url.to_string(),
+            headers: Vec::new(),
+            service_id: "test".to_string(),
+            timestamp: None,
+        }
+    }
+
+    /// The headers as text, for comparing in tests.
+    pub(crate) fn header_strs(&self) -> Vec<(&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(n, v)| (n.as_str(), std::str::from_utf8(v).unwrap()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW: &[u8] = b"GET /seg1.m4s?x=1 HTTP/1.1\r\n\
+        Host: cdn.example.com\r\n\
+        CMCD-Request: bl=100\r\n\
+        X-Dup: 1\r\n\
+        x-dup: 2\r\n\
+        X-DUP: 3\r\n\
+        \r\n\
+        body";
+
+    #[test]
+    fn parse_complete() {
+        let req = HostRequest::parse(RAW, "svc").unwrap();
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.url, "/seg1.m4s?x=1");
+        assert_eq!(req.service_id, "svc");
+        assert_eq!(req.timestamp, None);
+        assert_eq!(
+            req.header_strs(),
+            [
+                ("Host", "cdn.example.com"),
+                ("CMCD-Request", "bl=100"),
+                ("X-Dup", "1"),
+                ("x-dup", "2"),
+                ("X-DUP", "3"),
+            ]
+        );
+
+        let (_, len) = HostRequest::parse_head(RAW, "svc").unwrap().unwrap();
+        assert_eq!(&RAW[len..], b"body");
+    }
+
+    #[test]
+    fn parse_partial() {
+        let partial = &RAW[..40];
+        assert!(HostRequest::parse_head(partial, "svc").unwrap().is_none());
+        let err = HostRequest::parse(partial, "svc").unwrap_err();
+        assert_eq!(err.to_string(), "incomplete HTTP request head");
+        assert!(HostRequest::parse(b"GET\0 / HTTP/1.1\r\n\r\n", "svc").is_err());
+    }
+
+    #[test]
+    fn header_lookup_ignores_case() {
+        let req = HostRequest::parse(RAW, "svc").unwrap();
+        assert_eq!(req.header("host"), Some(vec![b"cdn.example.com".to_vec()]));
+        assert_eq!(req.header("cmcd-REQUEST"), Some(vec![b"bl=100".to_vec()]));
+        assert_eq!(req.header("content-length"), None);
+    }
+
+    #[test]
+    fn repeated_headers_keep_every_value_in_order() {
+        let req = HostRequest::parse(RAW, "svc").unwrap();
+        assert_eq!(
+            req.header("X-Dup"),
+            Some(vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()])
+        );
+    }
+
+    #[test]
+    fn header_names_are_deduplicated_ignoring_case() {
+        let req = HostRequest::parse(RAW, "svc").unwrap();
+        assert_eq!(
+            req.header_names(),
+            [
+                b"Host".to_vec(),
+                b"CMCD-Request".to_vec(),
+                b"X-Dup".to_vec()
+            ]
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::{anyhow, bail, Context, Error};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::event::{Events, HostEvent};
+use crate::queue::QueueSender;
+use crate::request::HostRequest;
+
+/// Largest request head accepted before the connection is refused.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// Accepts connections until the queue is closed, queueing every request
+/// received on them. Failed connections are reported to `events`.
+pub async fn accept_loop(
+    listener: TcpListener,
+    tx: QueueSender,
+    service_id: String,
+    events: Events,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = tx.closed() => return,
+        };
+        let (stream, peer) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                events.emit(HostEvent::AcceptFailed(e));
+                continue;
+            }
+        };
+        let tx = tx.clone();
+        let service_id = service_id.clone();
+        let events = events.clone();
+        tokio::spawn(async move {
+            if let Err(error) = handle_connection(stream, tx, service_id).await {
+                events.emit(HostEvent::ConnectionFailed { peer, error });
+            }
+        });
+    }
+}
+
+/// Reads requests off a keep-alive connection, queues each one and answers
+/// with an empty 204 once it's in the queue. Request bodies are discarded.
+async fn handle_connection(
+    mut stream: TcpStream,
+    tx: QueueSender,
+    service_id: String,
+) -> Result<(), Error> {
+    let mut buf = Vec::new();
+    loop {
+        let (req, head_len) = loop {
+            match HostRequest::parse_head(&buf, service_id.as_str()) {
+                Ok(Some(parsed)) => break parsed,
+                Ok(None) if buf.len() > MAX_HEAD => {
+                    return respond(&mut stream, "431 Request Header Fields Too Large").await;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    respond(&mut stream, "400 Bad Request").await?;
+                    return Err(e);
+                }
+            }
+            if stream.read_buf(&mut buf).await? == 0 {
+                return Ok(());
+            }
+        };
+
+        if req.header("transfer-encoding").is_some() {
+            return respond(&mut stream, "501 Not Implemented").await;
+        }
+        let body_len = match req.header("content-length").map(|v| content_length(&v)) {
+            Some(Ok(len)) => len,
+            Some(Err(e)) => {
+                respond(&mut stream, "400 Bad Request").await?;
+                return Err(e);
+            }
+            None => 0,
+        };
+        buf.drain(..head_len);
+        // Bodies aren't kept, so whatever isn't already buffered is skipped
+        // as it arrives rather than read into memory.
+        let buffered = buf
+            .len()
+            .min(usize::try_from(body_len).unwrap_or(usize::MAX));
+        buf.drain(..buffered);
+        let unread = body_len - buffered as u64;
+        let skipped =
+            tokio::io::copy(&mut (&mut stream).take(unread), &mut tokio::io::sink()).await?;
+        if skipped < unread {
+            return Err(anyhow!("connection closed before the body was read"));
+        }
+
+        let close = req
+            .header("connection")
+            .is_some_and(|v| v.iter().any(|v| v.eq_ignore_ascii_case(b"close")));
+
+        if tx.send(req).await.is_err() {
+            return respond(&mut stream, "503 Service Unavailable").await;
+        }
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+        if close {
+            return Ok(());
+        }
+    }
+}
+
+/// The body length given by every `Content-Length` header of a request.
+/// Lengths that disagree are refused, since a proxy in front of the host may
+/// have framed the request by a different one.
+fn content_length(values: &[Vec<u8>]) -> Result<u64, Error> {
+    let mut lengths = values.iter().map(|value| {
+        std::str::from_utf8(value)
+            .ok()
+            .map(str::trim)
+            .filter(|v| !v.is_empty() && v.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|v| v.parse::<u64>().ok())
+            .ok_or_else(|| anyhow!("bad content-length: {}", String::from_utf8_lossy(value)))
+    });
+    let first = lengths.next().context("no content-length")??;
+    for length in lengths {
+        if length? != first {
+            bail!("conflicting content-length headers");
+        }
+    }
+    Ok(first)
+}
+
+/// Sends an empty response and closes the connection.
+async fn respond(stream: &mut TcpStream, status: &str) -> Result<(), Error> {
+    let response = format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::queue::work_queue;
+
+    /// Sends `input` on a fresh connection, closes the sending side, and
+    /// returns everything the host answered along with what it queued.
+    async fn exchange(input: &[u8]) -> (String, Vec<HostRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (tx, queue) = work_queue(16);
+        let handled = tokio::spawn(handle_connection(server, tx, "svc".to_string()));
+
+        client.write_all(input).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let _ = handled.await.unwrap();
+
+        let mut queued = Vec::new();
+        while let Some(req) = queue.pop(Duration::from_millis(10)).await {
+            queued.push(req);
+        }
+        (response, queued)
+    }
+
+    const NO_CONTENT: &str = "HTTP/1.1 204 No Content\r\n\r\n";
+
+    #[tokio::test]
+    async fn bodyless_requests() {
+        let (response, queued) =
+            exchange(b"GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b HTTP/1.1\r\nHost: x\r\n\r\n").await;
+        assert_eq!(response, NO_CONTENT.repeat(2));
+        let urls: Vec<_> = queued.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(urls, ["/a", "/b"]);
+        assert_eq!(queued[0].service_id, "svc");
+    }
+
+    #[tokio::test]
+    async fn bodies_are_skipped() {
+        let body = "x".repeat(100_000);
+        let input = format!(
+            "POST /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}GET /next HTTP/1.1\r\n\r\n",
+            body.len()
+        );
+        let (response, queued) = exchange(input.as_bytes()).await;
+        assert_eq!(response, NO_CONTENT.repeat(2));
+        let urls: Vec<_> = queued.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(urls, ["/upload", "/next"]);
+    }
+
+    #[tokio::test]
+    async fn bad_content_length() {
+        for input in [
+            "POST / HTTP/1.1\r\nContent-Length: ten\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: +4\r\n\r\nbody",
+            "POST / HTTP/1.1\r\nContent-Length: 4\r\nContent-Length: 5\r\n\r\nbody!",
+            "POST / HTTP/1.1\r\nContent-Length: 4\r\nContent-Length:\r\n\r\nbody",
+        ] {
+            let (response, queued) = exchange(input.as_bytes()).await;
+            assert!(
+                response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+                "{input:?}: {response}"
+            );
+            assert!(queued.is_empty(), "{input:?}");
+        }
+
+        // Repeating the same length is fine.
+        let input = "POST / HTTP/1.1\r\nContent-Length: 4\r\ncontent-length: 4\r\n\r\nbody";
+        let (response, queued) = exchange(input.as_bytes()).await;
+        assert_eq!(response, NO_CONTENT);
+        assert_eq!(queued.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn truncated_body() {
+        let (response, queued) =
+            exchange(b"POST / HTTP/1.1\r\nContent-Length: 100\r\n\r\nshort").await;
+        assert_eq!(response, "");
+        assert!(queued.is_empty());
+    }
+}
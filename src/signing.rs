@@ -0,0 +1,247 @@
+//! Ed25519 signatures for compiled guests.
+//!
+//! Deserializing a `.cwasm` runs whatever machine code it holds. A host
+//! configured with trusted keys only loads artifacts whose metadata is
+//! signed by one of them; see [`ArtifactMeta::check_signature`].
+//!
+//! Keys are local files holding 32 bytes in hex. `host keygen KEY` writes a
+//! secret key to `KEY`, for `host compile --sign-key`, and its public key to
+//! `KEY.pub`, for `--trusted-key` or `engine.trusted_keys`.
+//!
+//! [`ArtifactMeta::check_signature`]: crate::artifact::ArtifactMeta::check_signature
+
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Error};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+
+use crate::artifact::{hex, parse_hex, ArtifactSignature};
+
+/// Where the public half of the secret key at `secret` is written.
+pub fn public_key_path(secret: &Path) -> PathBuf {
+    let mut path = secret.as_os_str().to_owned();
+    path.push(".pub");
+    PathBuf::from(path)
+}
+
+/// Generates a key pair, writing the secret key to `secret`, readable only
+/// by its owner, and the public key next to it. Returns where the public
+/// key went. Existing keys are never overwritten.
+pub fn generate_key(secret: &Path) -> Result<PathBuf, Error> {
+    let mut seed = [0; 32];
+    getrandom::getrandom(&mut seed).context("generating a key")?;
+    let key = SigningKey::from_bytes(&seed);
+
+    let public = public_key_path(secret);
+    write_new(secret, &hex(key.as_bytes()), 0o600)?;
+    write_new(&public, &hex(key.verifying_key().as_bytes()), 0o644)?;
+    Ok(public)
+}
+
+fn write_new(path: &Path, text: &str, mode: u32) -> Result<(), Error> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{text}"))
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+pub fn read_signing_key(path: &Path) -> Result<SigningKey, Error> {
+    let bytes =
+        read_key(path).with_context(|| format!("reading signing key {}", path.display()))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+pub fn read_verifying_key(path: &Path) -> Result<VerifyingKey, Error> {
+    let bytes =
+        read_key(path).with_context(|| format!("reading trusted key {}", path.display()))?;
+    VerifyingKey::from_bytes(&bytes)
+        .with_context(|| format!("{} isn't an Ed25519 public key", path.display()))
+}
+
+fn read_key(path: &Path) -> Result<[u8; 32], Error> {
+    let text = std::fs::read_to_string(path)?;
+    parse_hex(text.trim())
+        .and_then(|bytes| bytes.try_into().ok())
+        .context("expected 32 bytes in hex, as written by `host keygen`")
+}
+
+/// Signs `message`, recording which key did so.
+pub fn sign(key: &SigningKey, message: &[u8]) -> ArtifactSignature {
+    use ed25519_dalek::Signer;
+
+    ArtifactSignature {
+        key: hex(key.verifying_key().as_bytes()),
+        ed25519: hex(&key.sign(message).to_bytes()),
+    }
+}
+
+/// The public keys artifacts have to be signed with. Empty means signatures
+/// aren't checked.
+#[derive(Debug, Default)]
+pub struct TrustedKeys {
+    keys: Vec<(PathBuf, VerifyingKey)>,
+}
+
+impl TrustedKeys {
+    pub fn load(paths: &[PathBuf]) -> Result<Self, Error> {
+        let keys = paths
+            .iter()
+            .map(|path| Ok((path.clone(), read_verifying_key(path)?)))
+            .collect::<Result<_, Error>>()?;
+        Ok(TrustedKeys { keys })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Checks that `signature` over `message` was made by one of the keys,
+    /// returning the file it was read from.
+    pub fn verify(&self, message: &[u8], signature: &ArtifactSignature) -> Result<&Path, Error> {
+        let Some((path, key)) = self
+            .keys
+            .iter()
+            .find(|(_, key)| hex(key.as_bytes()) == signature.key)
+        else {
+            bail!(
+                "it's signed by key {}, which isn't one of the trusted keys",
+                signature.key
+            );
+        };
+        let signature = parse_hex(&signature.ed25519)
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .context("its signature isn't valid hex-encoded Ed25519")?;
+        if key.verify_strict(message, &signature).is_err() {
+            bail!(
+                "its signature by {} doesn't match its contents",
+                path.display()
+            );
+        }
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::artifact::ArtifactMeta;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    fn trusting(keys: &[&SigningKey]) -> TrustedKeys {
+        TrustedKeys {
+            keys: keys
+                .iter()
+                .enumerate()
+                .map(|(i, k)| (PathBuf::from(format!("key{i}.pub")), k.verifying_key()))
+                .collect(),
+        }
+    }
+
+    fn meta() -> ArtifactMeta {
+        ArtifactMeta {
+            source: PathBuf::from("/guests/trace.wasm"),
+            source_sha256: "11".repeat(32),
+            artifact_sha256: "22".repeat(32),
+            wasmtime: "24.0.13".to_string(),
+            engine: "33".repeat(32),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn valid_signature() {
+        let (release, other) = (key(1), key(2));
+        let mut meta = meta();
+        meta.sign(&release);
+        meta.check_signature(&trusting(&[&other, &release]))
+            .unwrap();
+        let signature = meta.signature.as_ref().unwrap();
+        let path = trusting(&[&other, &release])
+            .verify(&meta.signed_message(), signature)
+            .unwrap()
+            .to_path_buf();
+        assert_eq!(path, Path::new("key1.pub"));
+    }
+
+    #[test]
+    fn unsigned() {
+        let err = meta().check_signature(&trusting(&[&key(1)])).unwrap_err();
+        assert!(err.to_string().starts_with("it isn't signed"), "{err}");
+    }
+
+    #[test]
+    fn untrusted_key() {
+        let mut meta = meta();
+        meta.sign(&key(2));
+        let err = meta.check_signature(&trusting(&[&key(1)])).unwrap_err();
+        assert!(
+            err.to_string().contains("isn't one of the trusted keys"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn tampered_metadata() {
+        let release = key(1);
+        let trusted = trusting(&[&release]);
+        let mut meta = meta();
+        meta.sign(&release);
+
+        let mut tampered = meta.clone();
+        tampered.artifact_sha256 = "44".repeat(32);
+        let err = tampered.check_signature(&trusted).unwrap_err();
+        assert!(
+            err.to_string().contains("doesn't match its contents"),
+            "{err}"
+        );
+
+        let mut tampered = meta.clone();
+        tampered.engine = "55".repeat(32);
+        assert!(tampered.check_signature(&trusted).is_err());
+
+        // A signature claiming to be from a trusted key but made by another.
+        let mut forged = meta.clone();
+        forged.sign(&key(2));
+        forged.signature.as_mut().unwrap().key = hex(release.verifying_key().as_bytes());
+        assert!(forged.check_signature(&trusted).is_err());
+
+        let mut garbled = meta;
+        garbled.signature.as_mut().unwrap().ed25519 = "zz".to_string();
+        let err = garbled.check_signature(&trusted).unwrap_err();
+        assert!(
+            err.to_string().contains("isn't valid hex-encoded Ed25519"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn generated_keys_round_trip() {
+        let dir = std::env::temp_dir().join(format!("host-signing-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let secret = dir.join("release.key");
+
+        let public = generate_key(&secret).unwrap();
+        assert_eq!(public, dir.join("release.key.pub"));
+        assert!(generate_key(&secret).is_err(), "keys are never overwritten");
+
+        let signing = read_signing_key(&secret).unwrap();
+        let trusted = TrustedKeys::load(std::slice::from_ref(&public)).unwrap();
+        let mut meta = meta();
+        meta.sign(&signing);
+        meta.check_signature(&trusted).unwrap();
+
+        assert!(read_verifying_key(&secret.with_extension("missing")).is_err());
+        std::fs::write(&secret, "not hex\n").unwrap();
+        assert!(read_signing_key(&secret).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
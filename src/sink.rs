@@ -0,0 +1,853 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Error};
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::event::{Events, HostEvent};
+
+/// A single message logged by the guest through `trace-log.log`.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogRecord {
+    /// Milliseconds since the unix epoch at which the host received the message.
+    pub timestamp_ms: u128,
+    pub endpoint: String,
+    pub sid: String,
+    pub msg: String,
+}
+
+impl LogRecord {
+    pub fn new(msg: String, endpoint: String, sid: String) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        LogRecord {
+            timestamp_ms,
+            endpoint,
+            sid,
+            msg,
+        }
+    }
+
+    fn to_ndjson(&self) -> Result<Vec<u8>, Error> {
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        Ok(line)
+    }
+}
+
+/// How long a network sink may take to connect, or to send a message,
+/// before it's given up on.
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fails `op` if it takes longer than [`NETWORK_TIMEOUT`].
+async fn timed<T>(op: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+    tokio::time::timeout(NETWORK_TIMEOUT, op)
+        .await
+        .map_err(|_| anyhow!("timed out after {NETWORK_TIMEOUT:?}"))?
+}
+
+/// Somewhere guest log messages end up.
+#[async_trait::async_trait]
+pub trait LogSink: Send {
+    async fn write(&mut self, record: &LogRecord) -> Result<(), Error>;
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Prints each message to stdout, in the same shape the host always has.
+pub struct StdoutSink;
+
+#[async_trait::async_trait]
+impl LogSink for StdoutSink {
+    async fn write(&mut self, r: &LogRecord) -> Result<(), Error> {
+        println!(
+            "msg from guest is {}, to endpoint {} and sid {}",
+            r.msg, r.endpoint, r.sid
+        );
+        Ok(())
+    }
+}
+
+/// Appends one JSON object per message to a file.
+pub struct FileSink {
+    out: BufWriter<File>,
+}
+
+impl FileSink {
+    pub async fn open(path: &PathBuf) -> Result<Self, Error> {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("opening log file {}", path.display()))?;
+        Ok(FileSink {
+            out: BufWriter::new(file),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl LogSink for FileSink {
+    async fn write(&mut self, record: &LogRecord) -> Result<(), Error> {
+        self.out.write_all(&record.to_ndjson()?).await?;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        self.out.flush().await?;
+        Ok(())
+    }
+}
+
+/// Streams NDJSON to a listener on a unix domain socket. The connection is
+/// made lazily and re-established after a write failure or timeout.
+pub struct UnixSocketSink {
+    path: PathBuf,
+    stream: Option<UnixStream>,
+}
+
+impl UnixSocketSink {
+    pub fn new(path: PathBuf) -> Self {
+        UnixSocketSink { path, stream: None }
+    }
+}
+
+#[async_trait::async_trait]
+impl LogSink for UnixSocketSink {
+    async fn write(&mut self, record: &LogRecord) -> Result<(), Error> {
+        let line = record.to_ndjson()?;
+        let (path, stream) = (&self.path, &mut self.stream);
+        let sent = timed(async {
+            let stream = match stream {
+                Some(s) => s,
+                None => stream.insert(
+                    UnixStream::connect(path)
+                        .await
+                        .with_context(|| format!("connecting to {}", path.display()))?,
+                ),
+            };
+            stream.write_all(&line).await?;
+            Ok(())
+        })
+        .await;
+        // A message cut off part way leaves the listener mid-line, so start
+        // over on a new connection.
+        if sent.is_err() {
+            self.stream = None;
+        }
+        sent
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        if let Some(s) = &mut self.stream {
+            s.flush().await?;
+        }
+        Ok(())
+    }
+}
+
+/// POSTs each message as a JSON body to a plain-HTTP endpoint, typically a
+/// collector running on the same machine. The connection is kept open
+/// between messages when the collector allows it.
+pub struct HttpSink {
+    authority: String,
+    path: String,
+    stream: Option<TcpStream>,
+}
+
+/// Most bytes of response head read from a collector.
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+impl HttpSink {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("only http:// urls are supported, got {url}"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if authority.is_empty() {
+            bail!("missing host in {url}");
+        }
+        let authority = if authority.contains(':') {
+            authority.to_string()
+        } else {
+            format!("{authority}:80")
+        };
+        Ok(HttpSink {
+            authority,
+            path: path.to_string(),
+            stream: None,
+        })
+    }
+
+    /// Sends `request` and reads the response status, connecting first if
+    /// there's no open connection. Returns `None`, and forgets the
+    /// connection, if it was closed before any of the response arrived.
+    async fn exchange(&mut self, request: &[u8]) -> Result<Option<u16>, Error> {
+        let stream = match &mut self.stream {
+            Some(s) => s,
+            None => self.stream.insert(
+                TcpStream::connect(&self.authority)
+                    .await
+                    .with_context(|| format!("connecting to {}", self.authority))?,
+            ),
+        };
+        if let Err(e) = stream.write_all(request).await {
+            return match e.kind() {
+                ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => {
+                    self.stream = None;
+                    Ok(None)
+                }
+                _ => Err(e.into()),
+            };
+        }
+
+        let mut buf = Vec::new();
+        let (status, body, keep_alive) = loop {
+            if buf.len() >= MAX_RESPONSE_HEAD {
+                bail!("response head is larger than {MAX_RESPONSE_HEAD} bytes");
+            }
+            let read = match stream.read_buf(&mut buf).await {
+                Ok(n) => n,
+                Err(e) if buf.is_empty() && e.kind() == ErrorKind::ConnectionReset => 0,
+                Err(e) => return Err(e.into()),
+            };
+            if read == 0 {
+                if buf.is_empty() {
+                    self.stream = None;
+                    return Ok(None);
+                }
+                bail!("connection closed part way through the response");
+            }
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut response = httparse::Response::new(&mut headers);
+            let httparse::Status::Complete(head_len) =
+                response.parse(&buf).context("parsing response")?
+            else {
+                continue;
+            };
+            let status = response.code.context("response has no status")?;
+            let header = |name: &str| {
+                response
+                    .headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case(name))
+                    .map(|h| String::from_utf8_lossy(h.value).trim().to_ascii_lowercase())
+            };
+            let length = match header("content-length") {
+                Some(value) => Some(value.parse::<u64>().context("invalid Content-Length")?),
+                None if status == 204 || status == 304 => Some(0),
+                None => None,
+            };
+            let keep_alive = response.version == Some(1)
+                && header("connection").as_deref() != Some("close")
+                && length.is_some();
+            let already = (buf.len() - head_len) as u64;
+            break (
+                status,
+                length.map(|l| l.saturating_sub(already)),
+                keep_alive,
+            );
+        };
+
+        // Only a body of known length can be skipped to reuse the connection.
+        match body {
+            Some(left) if keep_alive => {
+                tokio::io::copy(&mut (&mut *stream).take(left), &mut tokio::io::sink()).await?;
+            }
+            _ => self.stream = None,
+        }
+        Ok(Some(status))
+    }
+}
+
+#[async_trait::async_trait]
+impl LogSink for HttpSink {
+    async fn write(&mut self, record: &LogRecord) -> Result<(), Error> {
+        let body = serde_json::to_vec(record)?;
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            self.path,
+            self.authority,
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(&body);
+
+        let reused = self.stream.is_some();
+        let status = timed(async {
+            match self.exchange(&request).await? {
+                Some(status) => Ok(status),
+                // The collector closed a kept-alive connection before it saw
+                // this message; send it once more on a new one.
+                None if reused => self.exchange(&request).await?.ok_or_else(|| {
+                    anyhow!(
+                        "{} closed the connection without responding",
+                        self.authority
+                    )
+                }),
+                None => bail!(
+                    "{} closed the connection without responding",
+                    self.authority
+                ),
+            }
+        })
+        .await;
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => {
+                self.stream = None;
+                return Err(e);
+            }
+        };
+        if !(200..300).contains(&status) {
+            bail!("{} responded with status {status}", self.authority);
+        }
+        Ok(())
+    }
+}
+
+/// Keeps messages in memory until they are taken with
+/// [`LogRouter::drain_buffer`], for hosts embedded in another program.
+/// Clones share the same messages.
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    records: Arc<Mutex<VecDeque<LogRecord>>>,
+}
+
+#[async_trait::async_trait]
+impl LogSink for LogBuffer {
+    async fn write(&mut self, record: &LogRecord) -> Result<(), Error> {
+        self.records.lock().unwrap().push_back(record.clone());
+        Ok(())
+    }
+}
+
+/// Where an endpoint's messages should go, as given on the command line or
+/// in the `[sinks]` table of the host config.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum SinkSpec {
+    Stdout,
+    File(PathBuf),
+    Unix(PathBuf),
+    Http(String),
+    /// The router's [`LogBuffer`].
+    Buffer,
+}
+
+impl SinkSpec {
+    /// Whether writes can stall on something outside the host, such as a
+    /// collector that has stopped reading.
+    pub fn is_remote(&self) -> bool {
+        matches!(self, SinkSpec::Unix(_) | SinkSpec::Http(_))
+    }
+
+    pub async fn open(&self, buffer: &LogBuffer) -> Result<Box<dyn LogSink>, Error> {
+        Ok(match self {
+            SinkSpec::Stdout => Box::new(StdoutSink),
+            SinkSpec::Buffer => Box::new(buffer.clone()),
+            SinkSpec::File(p) => Box::new(FileSink::open(p).await?),
+            SinkSpec::Unix(p) => Box::new(UnixSocketSink::new(p.clone())),
+            SinkSpec::Http(url) => Box::new(HttpSink::new(url)?),
+        })
+    }
+}
+
+impl FromStr for SinkSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "stdout" {
+            Ok(SinkSpec::Stdout)
+        } else if s == "buffer" {
+            Ok(SinkSpec::Buffer)
+        } else if let Some(p) = s.strip_prefix("file:") {
+            Ok(SinkSpec::File(p.into()))
+        } else if let Some(p) = s.strip_prefix("unix:") {
+            Ok(SinkSpec::Unix(p.into()))
+        } else if s.starts_with("http://") {
+            HttpSink::new(s)?;
+            Ok(SinkSpec::Http(s.to_string()))
+        } else {
+            bail!("unknown sink `{s}`; expected stdout, buffer, file:PATH, unix:PATH or http://HOST:PORT/PATH")
+        }
+    }
+}
+
+impl TryFrom<String> for SinkSpec {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Maps a guest endpoint name to a sink, in the form `ENDPOINT=SINK`.
+#[derive(Debug, Clone)]
+pub struct EndpointSink {
+    pub endpoint: String,
+    pub sink: SinkSpec,
+}
+
+impl FromStr for EndpointSink {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (endpoint, sink) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected ENDPOINT=SINK, got `{s}`"))?;
+        Ok(EndpointSink {
+            endpoint: endpoint.to_string(),
+            sink: sink.parse()?,
+        })
+    }
+}
+
+/// Messages a sink can fall behind by before new ones for it are dropped.
+const SINK_BACKLOG: usize = 1024;
+
+/// How long [`LogRouter::flush`] waits for a sink to catch up.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+enum SinkCommand {
+    Write(LogRecord),
+    Flush(oneshot::Sender<Result<(), Error>>),
+}
+
+/// Routes guest log messages to the sink configured for their endpoint.
+/// Messages for endpoints without a sink are dropped and counted.
+///
+/// Each sink is written from a task of its own, fed through a bounded
+/// channel. Once a sink has fallen `SINK_BACKLOG` messages behind, guests
+/// wait for it to catch up if it's local, but a remote sink's messages are
+/// dropped and counted instead, so guests never wait on a stalled
+/// collector.
+///
+/// Clones share the same sinks and counters, so every store can log through
+/// one router.
+#[derive(Clone, Default)]
+pub struct LogRouter {
+    inner: Arc<Routes>,
+    buffer: LogBuffer,
+}
+
+#[derive(Default)]
+struct Routes {
+    sinks: HashMap<String, Route>,
+    rejected: Mutex<HashMap<String, u64>>,
+    dropped: Mutex<HashMap<String, u64>>,
+    failed: Arc<AtomicU64>,
+    events: Events,
+}
+
+struct Route {
+    commands: mpsc::Sender<SinkCommand>,
+    /// Drop messages rather than wait while the sink is behind.
+    lossy: bool,
+}
+
+/// Counts one more message for `endpoint`, returning whether it's the first.
+fn count(counts: &Mutex<HashMap<String, u64>>, endpoint: &str) -> bool {
+    let mut counts = counts.lock().unwrap();
+    let count = counts.entry(endpoint.to_string()).or_default();
+    *count += 1;
+    *count == 1
+}
+
+/// Writes what the router sends for `endpoint` to its sink, until the router
+/// is dropped.
+async fn write_sink(
+    endpoint: String,
+    mut sink: Box<dyn LogSink>,
+    mut commands: mpsc::Receiver<SinkCommand>,
+    failed: Arc<AtomicU64>,
+    events: Events,
+) {
+    while let Some(command) = commands.recv().await {
+        match command {
+            SinkCommand::Write(record) => {
+                if let Err(error) = sink.write(&record).await {
+                    failed.fetch_add(1, Ordering::Relaxed);
+                    events.emit(HostEvent::SinkFailed {
+                        endpoint: endpoint.clone(),
+                        error,
+                    });
+                }
+            }
+            SinkCommand::Flush(done) => {
+                let _ = done.send(sink.flush().await);
+            }
+        }
+    }
+}
+
+impl LogRouter {
+    /// Opens a sink for each endpoint, reporting trouble with them to
+    /// `events`.
+    pub async fn from_specs(specs: &[EndpointSink], events: Events) -> Result<Self, Error> {
+        let mut routes = Routes {
+            events,
+            ..Routes::default()
+        };
+        let buffer = LogBuffer::default();
+        for spec in specs {
+            let sink = spec.sink.open(&buffer).await?;
+            let (tx, rx) = mpsc::channel(SINK_BACKLOG);
+            tokio::spawn(write_sink(
+                spec.endpoint.clone(),
+                sink,
+                rx,
+                routes.failed.clone(),
+                routes.events.clone(),
+            ));
+            let route = Route {
+                commands: tx,
+                lossy: spec.sink.is_remote(),
+            };
+            routes.sinks.insert(spec.endpoint.clone(), route);
+        }
+        Ok(LogRouter {
+            inner: Arc::new(routes),
+            buffer,
+        })
+    }
+
+    /// Takes every message logged to a `buffer` sink so far, oldest first.
+    pub fn drain_buffer(&self) -> Vec<LogRecord> {
+        self.buffer.records.lock().unwrap().drain(..).collect()
+    }
+
+    /// Hands `record` to the sink for its endpoint without waiting for it to
+    /// be written.
+    pub async fn log(&self, record: LogRecord) {
+        let Some((endpoint, route)) = self.inner.sinks.get_key_value(&record.endpoint) else {
+            if count(&self.inner.rejected, &record.endpoint) {
+                self.inner
+                    .events
+                    .emit(HostEvent::UnknownEndpoint(record.endpoint));
+            }
+            return;
+        };
+        let command = SinkCommand::Write(record);
+        let sent = if route.lossy {
+            route.commands.try_send(command).is_ok()
+        } else {
+            route.commands.send(command).await.is_ok()
+        };
+        if !sent && count(&self.inner.dropped, endpoint) {
+            self.inner
+                .events
+                .emit(HostEvent::SinkBehind(endpoint.clone()));
+        }
+    }
+
+    /// Waits for every sink to write what it has been sent, and flushes it.
+    pub async fn flush(&self) -> Result<(), Error> {
+        for (endpoint, route) in &self.inner.sinks {
+            let (done, flushed) = oneshot::channel();
+            let stopped = || anyhow!("its writer has stopped");
+            let result = tokio::time::timeout(FLUSH_TIMEOUT, async {
+                route
+                    .commands
+                    .send(SinkCommand::Flush(done))
+                    .await
+                    .map_err(|_| stopped())?;
+                flushed.await.map_err(|_| stopped())?
+            })
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("it didn't catch up within {FLUSH_TIMEOUT:?}")));
+            result.with_context(|| format!("flushing sink for endpoint {endpoint}"))?;
+        }
+        Ok(())
+    }
+
+    /// How many messages were rejected, dropped or failed to write so far.
+    pub fn stats(&self) -> LogStats {
+        LogStats {
+            rejected: self.inner.rejected.lock().unwrap().clone(),
+            dropped: self.inner.dropped.lock().unwrap().clone(),
+            failed: self.inner.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Log messages that went nowhere, from [`LogRouter::stats`].
+#[derive(Debug, Clone, Default)]
+pub struct LogStats {
+    /// Messages for each endpoint without a sink.
+    pub rejected: HashMap<String, u64>,
+    /// Messages for each endpoint whose remote sink had fallen behind.
+    pub dropped: HashMap<String, u64>,
+    /// Messages a sink failed to write.
+    pub failed: u64,
+}
+
+impl LogStats {
+    pub fn is_empty(&self) -> bool {
+        self.rejected.is_empty() && self.dropped.is_empty() && self.failed == 0
+    }
+}
+
+impl fmt::Display for LogStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = Vec::new();
+        for (endpoint, count) in &self.rejected {
+            lines.push(format!(
+                "rejected {count} log messages for unknown endpoint {endpoint}"
+            ));
+        }
+        for (endpoint, count) in &self.dropped {
+            lines.push(format!(
+                "dropped {count} log messages for endpoint {endpoint} while its sink was behind"
+            ));
+        }
+        if self.failed > 0 {
+            lines.push(format!("{} log messages could not be written", self.failed));
+        }
+        f.write_str(&lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn sink_spec_from_str() {
+        assert!(matches!("stdout".parse(), Ok(SinkSpec::Stdout)));
+        assert!(matches!("buffer".parse(), Ok(SinkSpec::Buffer)));
+        assert!(
+            matches!("file:/tmp/log.ndjson".parse(), Ok(SinkSpec::File(p)) if p == Path::new("/tmp/log.ndjson"))
+        );
+        assert!(
+            matches!("unix:/run/logs.sock".parse(), Ok(SinkSpec::Unix(p)) if p == Path::new("/run/logs.sock"))
+        );
+        assert!(
+            matches!("http://127.0.0.1:9000/logs".parse(), Ok(SinkSpec::Http(u)) if u == "http://127.0.0.1:9000/logs")
+        );
+        for bad in [
+            "",
+            "stderr",
+            "https://example.com/",
+            "http:///logs",
+            "tcp:127.0.0.1:9000",
+        ] {
+            assert!(bad.parse::<SinkSpec>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn endpoint_sink_from_str() {
+        let e: EndpointSink = "cmcd=file:a=b.ndjson".parse().unwrap();
+        assert_eq!(e.endpoint, "cmcd");
+        assert!(matches!(e.sink, SinkSpec::File(p) if p == Path::new("a=b.ndjson")));
+        assert!("cmcd".parse::<EndpointSink>().is_err());
+        assert!("cmcd=nowhere".parse::<EndpointSink>().is_err());
+    }
+
+    #[test]
+    fn http_sink_url() {
+        let sink = HttpSink::new("http://collector/logs?v=1").unwrap();
+        assert_eq!(sink.authority, "collector:80");
+        assert_eq!(sink.path, "/logs?v=1");
+        let sink = HttpSink::new("http://127.0.0.1:9000").unwrap();
+        assert_eq!(sink.authority, "127.0.0.1:9000");
+        assert_eq!(sink.path, "/");
+    }
+
+    /// Reads one request with a body off `stream`, returning false once the
+    /// client has closed the connection.
+    async fn read_request(stream: &mut TcpStream, buf: &mut Vec<u8>) -> bool {
+        loop {
+            let mut headers = [httparse::EMPTY_HEADER; 16];
+            let mut req = httparse::Request::new(&mut headers);
+            if let httparse::Status::Complete(head_len) = req.parse(buf).unwrap() {
+                let length: usize = req
+                    .headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case("content-length"))
+                    .map(|h| std::str::from_utf8(h.value).unwrap().parse().unwrap())
+                    .unwrap();
+                if buf.len() >= head_len + length {
+                    buf.drain(..head_len + length);
+                    return true;
+                }
+            }
+            if stream.read_buf(buf).await.unwrap() == 0 {
+                return false;
+            }
+        }
+    }
+
+    /// Serves connections with `responses`, in order, and returns how many
+    /// connections it took.
+    async fn collector(responses: Vec<&'static str>) -> (String, tokio::task::JoinHandle<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/logs", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut responses = responses.into_iter().peekable();
+            let mut connections = 0;
+            while responses.peek().is_some() {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                connections += 1;
+                let mut buf = Vec::new();
+                while read_request(&mut stream, &mut buf).await {
+                    let response = responses.next().unwrap();
+                    stream.write_all(response.as_bytes()).await.unwrap();
+                    if response.contains("close") || responses.peek().is_none() {
+                        break;
+                    }
+                }
+            }
+            connections
+        });
+        (url, server)
+    }
+
+    fn record() -> LogRecord {
+        LogRecord::new("hello".into(), "cmcd".into(), "sid".into())
+    }
+
+    #[tokio::test]
+    async fn http_sink_keeps_connection_alive() {
+        let (url, server) = collector(vec![
+            "HTTP/1.1 204 No Content\r\n\r\n",
+            "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok",
+            "HTTP/1.1 200 OK\r\nconnection: close\r\ncontent-length: 0\r\n\r\n",
+            "HTTP/1.1 204 No Content\r\n\r\n",
+        ])
+        .await;
+        let mut sink = HttpSink::new(&url).unwrap();
+        for _ in 0..4 {
+            sink.write(&record()).await.unwrap();
+        }
+        assert_eq!(server.await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn http_sink_checks_status() {
+        let (url, _server) = collector(vec![
+            "HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n",
+            "SMTP ready\r\n\r\n",
+        ])
+        .await;
+        let mut sink = HttpSink::new(&url).unwrap();
+        let err = sink.write(&record()).await.unwrap_err();
+        assert!(
+            err.to_string().ends_with("responded with status 503"),
+            "{err}"
+        );
+        let err = sink.write(&record()).await.unwrap_err();
+        assert!(
+            format!("{err:#}").starts_with("parsing response"),
+            "{err:#}"
+        );
+    }
+
+    /// An [`Events`] that keeps what it's given, as text.
+    fn captured() -> (Events, Arc<Mutex<Vec<String>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let events = Events::new({
+            let seen = seen.clone();
+            move |event| seen.lock().unwrap().push(event.to_string())
+        });
+        (events, seen)
+    }
+
+    fn to(endpoint: &str, msg: &str) -> LogRecord {
+        LogRecord::new(msg.into(), endpoint.into(), "sid".into())
+    }
+
+    #[tokio::test]
+    async fn router_rejects_unknown_endpoints() {
+        let (events, seen) = captured();
+        let specs = ["cmcd=buffer".parse().unwrap()];
+        let router = LogRouter::from_specs(&specs, events).await.unwrap();
+        for (endpoint, msg) in [
+            ("cmcd", "a"),
+            ("other", "b"),
+            ("other", "c"),
+            ("else", "d"),
+            ("cmcd", "e"),
+            ("other", "f"),
+        ] {
+            router.log(to(endpoint, msg)).await;
+        }
+        router.flush().await.unwrap();
+
+        let msgs: Vec<_> = router.drain_buffer().into_iter().map(|r| r.msg).collect();
+        assert_eq!(msgs, ["a", "e"]);
+        let stats = router.stats();
+        assert_eq!(
+            stats.rejected,
+            HashMap::from([("other".to_string(), 3), ("else".to_string(), 1)])
+        );
+        assert!(stats.dropped.is_empty());
+        assert_eq!(stats.failed, 0);
+        // Each unknown endpoint is only reported the first time.
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                "rejecting guest logs for unknown endpoint other",
+                "rejecting guest logs for unknown endpoint else",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn router_counts_failed_writes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let (events, seen) = captured();
+        let specs = [format!("cmcd=http://{addr}/logs").parse().unwrap()];
+        let router = LogRouter::from_specs(&specs, events).await.unwrap();
+        router.log(record()).await;
+        router.flush().await.unwrap();
+
+        assert_eq!(router.stats().failed, 1);
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert!(
+            seen[0].starts_with("failed to write log for endpoint cmcd: "),
+            "{}",
+            seen[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_router_rejects_everything() {
+        let router = LogRouter::default();
+        router.log(record()).await;
+        router.flush().await.unwrap();
+        assert_eq!(
+            router.stats().rejected,
+            HashMap::from([("cmcd".to_string(), 1)])
+        );
+        assert!(router.drain_buffer().is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+
+use anyhow::Error;
+use serde::Serialize;
+use wasmtime::{FrameInfo, Trap, WasmBacktrace, WasmCoreDump};
+
+use crate::request::HostRequest;
+
+/// How trap reports are rendered.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrapFormat {
+    /// Indented, multi-line text.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Everything known about why a call into the guest failed.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrapReport {
+    /// The wasm trap code, such as `UnreachableCodeReached`, when the guest
+    /// trapped rather than failing in the host.
+    pub trap: Option<String>,
+    /// The full error chain.
+    pub message: String,
+    pub frames: Vec<TrapFrame>,
+    /// The queued request the guest was handling, if any.
+    pub request: Option<RequestSummary>,
+    /// File name of the core dump written alongside this report.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coredump: Option<String>,
+}
+
+/// One wasm stack frame, innermost first.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrapFrame {
+    pub module: Option<String>,
+    pub func_index: u32,
+    /// Demangled name of the function, from the name section.
+    pub func_name: Option<String>,
+    pub module_offset: Option<usize>,
+    /// Source locations from DWARF, innermost inlined call first.
+    pub source: Vec<SourceLocation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceLocation {
+    pub function: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+/// The parts of a request worth identifying it by in a report.
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestSummary {
+    pub method: String,
+    pub url: String,
+    pub service_id: String,
+}
+
+impl From<&HostRequest> for RequestSummary {
+    fn from(req: &HostRequest) -> Self {
+        RequestSummary {
+            method: req.method.clone(),
+            url: req.url.clone(),
+            service_id: req.service_id.clone(),
+        }
+    }
+}
+
+fn demangle(name: &str) -> String {
+    // The alternate form leaves off the trailing hash.
+    format!("{:#}", rustc_demangle::demangle(name))
+}
+
+impl TrapFrame {
+    fn new(frame: &FrameInfo) -> Self {
+        TrapFrame {
+            module: frame.module().name().map(str::to_string),
+            func_index: frame.func_index(),
+            func_name: frame.func_name().map(demangle),
+            module_offset: frame.module_offset(),
+            source: frame
+                .symbols()
+                .iter()
+                .map(|s| SourceLocation {
+                    function: s.name().map(demangle),
+                    file: s.file().map(str::to_string),
+                    line: s.line(),
+                    column: s.column(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TrapReport {
+    pub fn new(error: &Error, request: Option<RequestSummary>) -> Self {
+        let trap = error
+            .chain()
+            .find_map(|e| e.downcast_ref::<Trap>())
+            .map(|t| format!("{t:?}"));
+        let backtrace = error.downcast_ref::<WasmBacktrace>();
+        let frames = backtrace
+            .map(|bt| bt.frames().iter().map(TrapFrame::new).collect())
+            .unwrap_or_default();
+        // The backtrace is reported frame by frame and the core dump is
+        // written out separately, so leave both out here.
+        let attached = [
+            backtrace.map(|bt| bt.to_string()),
+            error.downcast_ref::<WasmCoreDump>().map(|d| d.to_string()),
+        ];
+        let message = error
+            .chain()
+            .map(|e| e.to_string())
+            .filter(|e| !attached.iter().flatten().any(|a| a == e))
+            .collect::<Vec<_>>()
+            .join(": ");
+        TrapReport {
+            trap,
+            message,
+            frames,
+            request,
+            coredump: None,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    /// The report as `format` has it, without a trailing newline.
+    pub fn render(&self, format: TrapFormat) -> String {
+        match format {
+            TrapFormat::Text => self.to_string(),
+            TrapFormat::Json => self
+                .to_json()
+                .unwrap_or_else(|e| format!("failed to serialize trap report: {e}")),
+        }
+    }
+}
+
+impl fmt::Display for TrapReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.trap {
+            Some(trap) => write!(f, "guest trapped ({trap}): {}", self.message)?,
+            None => write!(f, "guest failed: {}", self.message)?,
+        }
+        if let Some(coredump) = &self.coredump {
+            write!(f, "\n  core dump written to {coredump}")?;
+        }
+        if let Some(req) = &self.request {
+            write!(
+                f,
+                "\n  while handling {} {} for {}",
+                req.method, req.url, req.service_id
+            )?;
+        }
+        for (i, frame) in self.frames.iter().enumerate() {
+            let name = frame.func_name.as_deref().unwrap_or("<unknown>");
+            write!(f, "\n  {i:>3}: ")?;
+            if let Some(offset) = frame.module_offset {
+                write!(f, "{offset:#x} - ")?;
+            }
+            if let Some(module) = &frame.module {
+                write!(f, "{module}!")?;
+            }
+            write!(f, "{name}")?;
+            for loc in &frame.source {
+                if let Some(function) = &loc.function {
+                    write!(f, "\n           {function}")?;
+                }
+                if let Some(file) = &loc.file {
+                    write!(f, "\n             at {file}")?;
+                    if let Some(line) = loc.line {
+                        write!(f, ":{line}")?;
+                        if let Some(column) = loc.column {
+                            write!(f, ":{column}")?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
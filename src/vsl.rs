@@ -0,0 +1,271 @@
+//! Reads client requests back out of `varnishlog` text output.
+//!
+//! Both the grouped layouts (`-g vxid`, the default, and `-g request`) and
+//! `-g raw` are understood. Only client request transactions are replayed;
+//! sessions and backend requests are skipped. `ReqHeader` and `ReqUnset`
+//! records are applied in order, so the replayed request carries the headers
+//! the request had when its transaction ended.
+
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Error};
+
+use crate::request::HostRequest;
+
+#[derive(Default)]
+struct Transaction {
+    is_request: bool,
+    method: Option<String>,
+    url: Option<String>,
+    headers: Vec<(String, Vec<u8>)>,
+    started: Option<SystemTime>,
+}
+
+impl Transaction {
+    fn apply(&mut self, tag: &str, data: &str) {
+        match tag {
+            "ReqMethod" => self.method = Some(data.to_string()),
+            "ReqURL" => self.url = Some(data.to_string()),
+            "ReqHeader" => {
+                if let Some((name, value)) = split_header(data) {
+                    self.headers
+                        .push((name.to_string(), value.as_bytes().to_vec()));
+                }
+            }
+            "ReqUnset" => {
+                if let Some((name, value)) = split_header(data) {
+                    let unset = self
+                        .headers
+                        .iter()
+                        .position(|(n, v)| n.eq_ignore_ascii_case(name) && v == value.as_bytes());
+                    if let Some(i) = unset {
+                        self.headers.remove(i);
+                    }
+                }
+            }
+            "Timestamp" => {
+                // "Start: <absolute> <since start> <since last>"
+                let start = data
+                    .strip_prefix("Start:")
+                    .and_then(|rest| rest.split_whitespace().next())
+                    .and_then(|secs| secs.parse::<f64>().ok())
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+                if let Some(start) = start {
+                    self.started = Some(UNIX_EPOCH + start);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn into_request(self, service_id: &str) -> Option<HostRequest> {
+        Some(HostRequest {
+            method: self.method?,
+            url: self.url?,
+            headers: self.headers,
+            service_id: service_id.to_string(),
+            timestamp: self.started,
+        })
+    }
+}
+
+fn split_header(data: &str) -> Option<(&str, &str)> {
+    let (name, value) = data.split_once(':')?;
+    Some((name.trim(), value.trim_start()))
+}
+
+/// Splits a log line into the key of the transaction it belongs to, its tag
+/// and its payload. Grouped output is keyed by nesting depth, raw output by
+/// vxid. Transaction headers, blank lines and anything else unrecognised
+/// yield `None`.
+fn record(line: &str) -> Option<(u64, &str, &str)> {
+    let trimmed = line.trim_start();
+    let first = trimmed.split_whitespace().next()?;
+
+    let (key, rest) = if first.bytes().all(|b| b == b'-') {
+        (first.len() as u64, &trimmed[first.len()..])
+    } else if let Ok(vxid) = first.parse::<u64>() {
+        // raw: "<vxid> <tag> <c|b|-> <data>"
+        let rest = trimmed[first.len()..].trim_start();
+        let (tag, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let rest = rest.trim_start();
+        let data = match rest.split_once(char::is_whitespace) {
+            Some((marker, data)) if marker.len() == 1 => data,
+            _ if rest.len() == 1 => "",
+            _ => rest,
+        };
+        return Some((vxid, tag, data.trim()));
+    } else {
+        return None;
+    };
+
+    let rest = rest.trim_start();
+    let (tag, data) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    Some((key, tag, data.trim()))
+}
+
+/// Parses every complete client request in a `varnishlog` dump.
+pub fn parse(reader: impl BufRead, service_id: &str) -> Result<Vec<HostRequest>, Error> {
+    let mut open: HashMap<u64, Transaction> = HashMap::new();
+    let mut requests = Vec::new();
+
+    for (n, line) in reader.lines().enumerate() {
+        let line = line.with_context(|| format!("reading line {}", n + 1))?;
+        let Some((key, tag, data)) = record(&line) else {
+            continue;
+        };
+        match tag {
+            "Begin" => {
+                let kind = data.split_whitespace().next().unwrap_or_default();
+                open.insert(
+                    key,
+                    Transaction {
+                        is_request: kind == "req",
+                        ..Default::default()
+                    },
+                );
+            }
+            "End" => {
+                let Some(txn) = open.remove(&key) else {
+                    continue;
+                };
+                if txn.is_request {
+                    if let Some(req) = txn.into_request(service_id) {
+                        requests.push(req);
+                    }
+                }
+            }
+            _ => {
+                if let Some(txn) = open.get_mut(&key) {
+                    txn.apply(tag, data);
+                }
+            }
+        }
+    }
+    Ok(requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GROUPED: &str = "\
+*   << Session  >> 32769
+-   Begin          sess 0 HTTP/1
+-   SessOpen       127.0.0.1 53142 a0 127.0.0.1 6081 1700000000.000000 22
+-   End
+
+*   << Request  >> 32770
+-   Begin          req 32769 rxreq
+-   Timestamp      Start: 1700000000.500000 0.000000 0.000000
+-   ReqMethod      GET
+-   ReqURL         /seg1.m4s?x=1
+-   ReqProtocol    HTTP/1.1
+-   ReqHeader      Host: localhost
+-   ReqHeader      Accept-Encoding: gzip
+-   ReqHeader      CMCD-Request: bl=21300
+-   ReqHeader      Accept-Encoding: br
+-   VCL_call       RECV
+-   ReqUnset       Accept-Encoding: gzip
+-   ReqHeader      X-Empty:
+-   Link           bereq 32771 fetch
+-   End
+**  << BeReq    >> 32771
+--  Begin          bereq 32770 fetch
+--  BereqMethod    GET
+--  BereqURL       /seg1.m4s
+--  End
+";
+
+    #[test]
+    fn grouped() {
+        let requests = parse(GROUPED.as_bytes(), "svc").unwrap();
+        assert_eq!(requests.len(), 1);
+        let req = &requests[0];
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.url, "/seg1.m4s?x=1");
+        assert_eq!(req.service_id, "svc");
+        assert_eq!(
+            req.timestamp,
+            Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_500))
+        );
+        assert_eq!(
+            req.header_strs(),
+            [
+                ("Host", "localhost"),
+                ("CMCD-Request", "bl=21300"),
+                ("Accept-Encoding", "br"),
+                ("X-Empty", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn grouped_request_with_nested_backend_request() {
+        // `-g request` nests the backend request inside the client request,
+        // before the client request's End.
+        let log = "\
+*   << Request  >> 5
+-   Begin          req 4 rxreq
+-   ReqMethod      GET
+-   ReqURL         /a
+**  << BeReq    >> 6
+--  Begin          bereq 5 fetch
+--  BereqURL       /b
+--  ReqURL         /not-the-client-url
+--  End
+-   ReqHeader      Host: example.com
+-   End
+";
+        let requests = parse(log.as_bytes(), "svc").unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].url, "/a");
+        assert_eq!(requests[0].header_strs(), [("Host", "example.com")]);
+    }
+
+    #[test]
+    fn raw() {
+        // Raw records from different transactions interleave.
+        let log = "\
+         0 CLI            - Rd ping
+     32769 Begin          c sess 0 HTTP/1
+     32772 Begin          c req 32769 rxreq
+     32773 Begin          c req 32769 rxreq
+     32772 ReqMethod      c POST
+     32773 ReqMethod      c GET
+     32772 ReqURL         c /raw
+     32773 ReqURL         c /second
+     32772 ReqHeader      c Host: raw
+     32772 ReqHeader      c X-Dup: 1
+     32772 ReqHeader      c X-Dup: 2
+     32772 ReqUnset       c x-dup: 1
+     32774 Begin          b bereq 32772 fetch
+     32774 BereqMethod    b POST
+     32774 End            b
+     32773 End            c
+     32772 End            c
+     32769 End            c
+";
+        let requests = parse(log.as_bytes(), "svc").unwrap();
+        let urls: Vec<_> = requests.iter().map(|r| r.url.as_str()).collect();
+        assert_eq!(urls, ["/second", "/raw"]);
+        assert_eq!(requests[1].method, "POST");
+        assert_eq!(requests[1].header_strs(), [("Host", "raw"), ("X-Dup", "2")]);
+        assert_eq!(requests[1].timestamp, None);
+    }
+
+    #[test]
+    fn incomplete_requests_are_skipped() {
+        let log = "\
+-   Begin          req 1 rxreq
+-   ReqURL         /no-method
+-   End
+-   Begin          req 1 rxreq
+-   ReqMethod      GET
+-   ReqURL         /never-ended
+";
+        assert!(parse(log.as_bytes(), "svc").unwrap().is_empty());
+    }
+}
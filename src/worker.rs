@@ -0,0 +1,144 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Error};
+use tokio::task::JoinSet;
+
+use crate::config::HostConfig;
+use crate::event::HostEvent;
+use crate::{Host, TraceInstance};
+
+/// How a long-running worker re-enters the guest.
+#[derive(Debug, Clone)]
+pub struct WorkerOptions {
+    /// Number of workers entering the guest at once, each with its own
+    /// store, all instantiated from the same pre-linked component and popping
+    /// from the same queue.
+    pub workers: usize,
+    /// Keep the same store and instance between calls to `enter` instead of
+    /// instantiating afresh each time. A trap always discards the instance.
+    pub reuse_store: bool,
+    /// Delay before re-instantiating after the first trap or failure to
+    /// instantiate in a row. Doubles with each consecutive one up to
+    /// `max_backoff`. Also how quickly a guest has to return without taking
+    /// a request to be backed off from.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for WorkerOptions {
+    fn default() -> Self {
+        WorkerOptions {
+            workers: 1,
+            reuse_store: false,
+            min_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl WorkerOptions {
+    /// Checks the options can be used with `config`, so a run can be turned
+    /// down before its guest is loaded.
+    pub fn check(&self, config: &HostConfig) -> Result<(), Error> {
+        if config.engine.profile.is_some() && self.workers > 1 {
+            bail!(
+                "`engine.profile` can only sample one worker's guest, but {} workers were asked for",
+                self.workers
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Runs `opts.workers` workers on their own tasks until the queue is closed
+/// and drained. If one of them fails, the rest are stopped.
+pub(crate) async fn run_workers(host: &Host, opts: &WorkerOptions) -> Result<(), Error> {
+    if opts.workers <= 1 {
+        return run_worker(host, opts).await;
+    }
+    let mut workers = JoinSet::new();
+    for id in 0..opts.workers {
+        let host = host.clone();
+        let opts = opts.clone();
+        workers.spawn(async move {
+            run_worker(&host, &opts)
+                .await
+                .with_context(|| format!("worker {id}"))
+        });
+    }
+    while let Some(result) = workers.join_next().await {
+        result.context("worker panicked")??;
+    }
+    Ok(())
+}
+
+/// Calls `trace-hooks.enter` over and over until the work queue is closed
+/// and drained, or the host is killed. Traps and failures to instantiate are
+/// reported and the guest restarted with backoff. A guest that returns
+/// straight away without taking a request is backed off from the same way,
+/// so it can't spin.
+async fn run_worker(host: &Host, opts: &WorkerOptions) -> Result<(), Error> {
+    let mut backoff = opts.min_backoff;
+    let mut reusable: Option<TraceInstance> = None;
+
+    while !host.queue().is_finished() && !host.is_killed() {
+        let instance = match reusable.take() {
+            // Once the guest has been reloaded, move on to the new version.
+            Some(instance) if instance.version() == host.version() => Ok(instance),
+            _ => host.instantiate().await,
+        };
+        let mut instance = match instance {
+            Ok(instance) => instance,
+            Err(error) => {
+                let killed = host.is_killed();
+                host.events().emit(HostEvent::InstantiateFailed {
+                    error,
+                    retry_in: (!killed).then_some(backoff),
+                });
+                if killed {
+                    break;
+                }
+                back_off(host, &mut backoff, opts).await;
+                continue;
+            }
+        };
+
+        let popped = instance.popped();
+        let entered = Instant::now();
+        match instance.enter().await {
+            Ok(()) => {
+                let idle = instance.popped() == popped && entered.elapsed() < opts.min_backoff;
+                if opts.reuse_store {
+                    reusable = Some(instance);
+                }
+                if idle && !host.queue().is_finished() {
+                    host.events().emit(HostEvent::Idle { retry_in: backoff });
+                    back_off(host, &mut backoff, opts).await;
+                } else {
+                    backoff = opts.min_backoff;
+                }
+            }
+            Err(e) => {
+                instance.report_trap(e);
+                if host.is_killed() {
+                    break;
+                }
+                host.events()
+                    .emit(HostEvent::Restarting { retry_in: backoff });
+                back_off(host, &mut backoff, opts).await;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Waits out `backoff`, then doubles it for next time. Cut short when the
+/// queue starts closing, so shutdown isn't held up.
+async fn back_off(host: &Host, backoff: &mut Duration, opts: &WorkerOptions) {
+    let queue = host.queue();
+    tokio::select! {
+        _ = tokio::time::sleep(*backoff) => {}
+        _ = queue.closing(), if !queue.is_closing() => {}
+    }
+    *backoff = (*backoff * 2).min(opts.max_backoff);
+}